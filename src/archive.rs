@@ -0,0 +1,137 @@
+use std::{path::Path, process::Stdio};
+
+use hyper::{body::Bytes, header, Body, Response};
+use tokio::io::AsyncReadExt;
+
+use crate::{error::Error, logging, util};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    const SUFFIXES: &'static [(&'static str, Format)] = &[
+        (".tar.zst", Format::TarZst),
+        (".tar.gz", Format::TarGz),
+        (".tar", Format::Tar),
+        (".zip", Format::Zip),
+    ];
+
+    /// Split `rpm.tar.zst` into `rpm` and the archive format.
+    pub fn split_suffix(name: &str) -> Option<(&str, Format)> {
+        Self::SUFFIXES.iter().find_map(|(suffix, format)| {
+            name.strip_suffix(suffix).map(|base| (base, *format))
+        })
+    }
+
+    fn suffix(&self) -> &'static str {
+        Self::SUFFIXES
+            .iter()
+            .find(|(_, format)| format == self)
+            .map(|(suffix, _)| *suffix)
+            .unwrap()
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Tar => "application/x-tar",
+            Format::TarGz => "application/gzip",
+            Format::TarZst => "application/zstd",
+            Format::Zip => "application/zip",
+        }
+    }
+
+    fn command(&self) -> &'static str {
+        match self {
+            Format::Tar => "tar -cf - .",
+            Format::TarGz => "tar -cf - . | gzip -c",
+            Format::TarZst => "tar -cf - . | zstd -q -c",
+            Format::Zip => "zip -q -r - .",
+        }
+    }
+}
+
+/// Write the directory as an archive file.
+pub fn write(dir: &Path, format: Format, out: &Path) -> Result<(), Error> {
+    let dir = dir.display();
+    let out = out.display();
+    util::bash(format!("set -o pipefail; cd {dir} && {} > {out}", format.command()))
+}
+
+/// Stream the directory as an archive, generated on the fly by the archiving
+/// tools without writing it anywhere. The directory is kept in use until the
+/// archive is done.
+pub fn serve(
+    dir: &Path,
+    format: Format,
+    name: &str,
+    in_use: util::InUse,
+) -> Result<Response<Body>, Error> {
+    let mut child = tokio::process::Command::new("bash")
+        .arg("-c")
+        .arg(format!("set -o pipefail; {}", format.command()))
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdout = child.stdout.take().unwrap();
+    let (mut sender, body) = Body::channel();
+    let dir = dir.to_owned();
+
+    logging::spawn(async move {
+        let _in_use = in_use;
+        let mut buf = vec![0u8; 0x10000];
+        loop {
+            match stdout.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send_data(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                        log::info!("archive: {}: client went away", dir.display());
+                        return;
+                    }
+                }
+                Err(err) => {
+                    log::error!("archive: {}: read error: {}", dir.display(), err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                log::error!("archive: {}: failed: {}", dir.display(), status);
+                sender.abort();
+            }
+            Err(err) => {
+                log::error!("archive: {}: failed: {}", dir.display(), err);
+                sender.abort();
+            }
+        }
+    });
+
+    let mut rsp = Response::new(body);
+    let headers = rsp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(format.content_type()),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        header::HeaderValue::from_str(&format!(
+            "attachment; filename=\"{}{}\"",
+            name,
+            format.suffix()
+        ))
+        .map_err(|e| Error::Boxed(std::sync::Arc::new(e)))?,
+    );
+
+    Ok(rsp)
+}
@@ -6,7 +6,7 @@
 
 use derive_builder::Builder;
 
-use gitlab::api::{common::NameOrId, endpoint_prelude::*};
+use gitlab::api::{common::NameOrId, endpoint_prelude::*, Pageable};
 
 /// Query for jobs within a pipeline.
 #[derive(Debug, Builder)]
@@ -34,7 +34,143 @@ impl<'a> Endpoint for JobArtifacts<'a> {
         format!("projects/{}/jobs/{}/artifacts", self.project, self.job).into()
     }
 
-    fn parameters(&self) -> QueryParams {
+    fn parameters(&self) -> QueryParams<'_> {
         QueryParams::default()
     }
 }
+
+/// Query for the packages of a project.
+#[derive(Debug, Builder)]
+pub struct ProjectPackages<'a> {
+    /// The project to query for packages.
+    #[builder(setter(into))]
+    project: NameOrId<'a>,
+    /// Filter packages by name.
+    #[builder(setter(into))]
+    package_name: Cow<'a, str>,
+    /// Filter packages by type.
+    #[builder(setter(into), default)]
+    package_type: Option<Cow<'a, str>>,
+}
+
+impl<'a> ProjectPackages<'a> {
+    /// Create a builder for the endpoint.
+    pub fn builder() -> ProjectPackagesBuilder<'a> {
+        ProjectPackagesBuilder::default()
+    }
+}
+
+impl<'a> Endpoint for ProjectPackages<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/packages", self.project).into()
+    }
+
+    fn parameters(&self) -> QueryParams<'_> {
+        let mut params = QueryParams::default();
+        params
+            .push("package_name", self.package_name.as_ref())
+            .push_opt("package_type", self.package_type.as_ref());
+        params
+    }
+}
+
+impl<'a> Pageable for ProjectPackages<'a> {}
+
+/// Query for the files of a package.
+#[derive(Debug, Builder)]
+pub struct PackageFiles<'a> {
+    /// The project of the package.
+    #[builder(setter(into))]
+    project: NameOrId<'a>,
+    /// The ID of the package.
+    package: u64,
+}
+
+impl<'a> PackageFiles<'a> {
+    /// Create a builder for the endpoint.
+    pub fn builder() -> PackageFilesBuilder<'a> {
+        PackageFilesBuilder::default()
+    }
+}
+
+impl<'a> Endpoint for PackageFiles<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/packages/{}/package_files", self.project, self.package).into()
+    }
+}
+
+impl<'a> Pageable for PackageFiles<'a> {}
+
+/// Download a file of a generic package.
+#[derive(Debug, Builder)]
+pub struct GenericPackageFile<'a> {
+    /// The project of the package.
+    #[builder(setter(into))]
+    project: NameOrId<'a>,
+    /// The name of the package.
+    #[builder(setter(into))]
+    package_name: Cow<'a, str>,
+    /// The version of the package.
+    #[builder(setter(into))]
+    package_version: Cow<'a, str>,
+    /// The name of the file.
+    #[builder(setter(into))]
+    file_name: Cow<'a, str>,
+}
+
+impl<'a> GenericPackageFile<'a> {
+    /// Create a builder for the endpoint.
+    pub fn builder() -> GenericPackageFileBuilder<'a> {
+        GenericPackageFileBuilder::default()
+    }
+}
+
+impl<'a> Endpoint for GenericPackageFile<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/packages/generic/{}/{}/{}",
+            self.project, self.package_name, self.package_version, self.file_name
+        )
+        .into()
+    }
+}
+
+/// Query for a release of a project by its tag.
+#[derive(Debug, Builder)]
+pub struct ProjectRelease<'a> {
+    /// The project of the release.
+    #[builder(setter(into))]
+    project: NameOrId<'a>,
+    /// The tag of the release.
+    #[builder(setter(into))]
+    tag: Cow<'a, str>,
+}
+
+impl<'a> ProjectRelease<'a> {
+    /// Create a builder for the endpoint.
+    pub fn builder() -> ProjectReleaseBuilder<'a> {
+        ProjectReleaseBuilder::default()
+    }
+}
+
+impl<'a> Endpoint for ProjectRelease<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/releases/{}", self.project, self.tag).into()
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+};
+
+use gitlab::api::AsyncQuery;
+
+use crate::{cmdline::CheckConfigArgs, config::Config, error::Error, status, util, ClientCache};
+
+/// Repo types, which the first component of a URL path can't be a source
+/// name for, along with the paths the server reserves.
+const KIND_NAMES: &[&str] = &["rpm", "files", "pacman"];
+
+/// External tools, and whether the server can't do without them.
+const TOOLS: &[(&str, bool, &str)] = &[
+    ("bash", true, "running the other tools"),
+    ("cp", true, "placing artifacts in composites"),
+    ("unzip", true, "extracting Gitlab, Gitea and Jenkins artifacts"),
+    ("tar", true, "archives and federation"),
+    ("sha256sum", true, "files repos"),
+    ("createrepo", true, "rpm repos"),
+    ("rpm", false, "rpm repo options"),
+    ("repo-add", false, "pacman repos"),
+    ("gzip", false, "tar.gz archives"),
+    ("zstd", false, "tar.zst archives"),
+    ("zip", false, "zip archives"),
+];
+
+#[derive(Default)]
+struct Report {
+    quiet: bool,
+    failures: Vec<String>,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, item: &str, detail: impl std::fmt::Display) {
+        if !self.quiet {
+            println!("[ OK ] {}: {}", item, detail);
+        }
+    }
+
+    fn warn(&mut self, item: &str, detail: impl std::fmt::Display) {
+        if !self.quiet {
+            println!("[WARN] {}: {}", item, detail);
+        }
+        self.warnings += 1;
+    }
+
+    fn fail(&mut self, item: &str, detail: impl std::fmt::Display) {
+        if !self.quiet {
+            println!("[FAIL] {}: {}", item, detail);
+        }
+        self.failures.push(format!("{}: {}", item, detail));
+    }
+
+    fn check<T, E: std::fmt::Display>(&mut self, item: &str, result: Result<T, E>, ok: &str) {
+        match result {
+            Ok(_) => self.ok(item, ok),
+            Err(err) => self.fail(item, err),
+        }
+    }
+}
+
+fn check_writable(dir: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(".check-config");
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(probe)?;
+    Ok(())
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    match parsed.scheme() {
+        "http" | "https" if parsed.host_str().is_some() => Ok(()),
+        "http" | "https" => Err("no host".to_owned()),
+        scheme => Err(format!("unsupported scheme {}", scheme)),
+    }
+}
+
+fn find_tool(name: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| {
+            std::fs::metadata(path).is_ok_and(|x| x.is_file() && x.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// The checks of the configuration itself, not needing the network.
+fn check_config(config: &Config, report: &mut Report) {
+    for (item, dir) in [
+        ("composites-cache", &config.composites_cache),
+        ("local-cache", &config.local_cache),
+    ] {
+        report.check(item, check_writable(dir), &format!("{} is writable", dir.display()));
+    }
+
+    match config.listen_addr.to_socket_addrs().map(|mut x| x.next()) {
+        Ok(Some(addr)) => report.ok("listen-addr", addr),
+        Ok(None) => report.fail("listen-addr", "resolves to no address"),
+        Err(err) => report.fail("listen-addr", err),
+    }
+
+    // Source names share the first component of URL paths, and a name that
+    // is in several maps only reaches the first of them.
+    let mut names: HashMap<&str, Vec<&str>> = HashMap::new();
+    let maps: [(&str, Vec<&String>); 8] = [
+        ("gitlabs", config.gitlabs.keys().collect()),
+        ("local-source", config.local_source.keys().collect()),
+        ("remote-source", config.remote_source.keys().collect()),
+        ("oci-source", config.oci_source.keys().collect()),
+        ("s3-source", config.s3_source.keys().collect()),
+        ("gitea-source", config.gitea_source.keys().collect()),
+        ("jenkins-source", config.jenkins_source.keys().collect()),
+        ("speardrive-source", config.speardrive_source.keys().collect()),
+    ];
+    for (map, keys) in maps.iter() {
+        for key in keys {
+            names.entry(key.as_str()).or_default().push(map);
+        }
+    }
+    let mut names: Vec<_> = names.into_iter().collect();
+    names.sort();
+    for (name, maps) in names {
+        let item = format!("source '{}'", name);
+        if KIND_NAMES.contains(&name) || status::RESERVED_NAMES.contains(&name) || name.is_empty() {
+            report.fail(&item, "name is reserved for URL paths");
+        } else if maps.len() > 1 {
+            report.fail(&item, format!("defined in more than one of {}", maps.join(", ")));
+        }
+    }
+
+    for (name, source) in config.local_source.iter() {
+        let item = format!("local-source '{}'", name);
+        if source.root.is_dir() {
+            report.ok(&item, format!("{} exists", source.root.display()));
+        } else {
+            report.fail(&item, format!("{} is not a directory", source.root.display()));
+        }
+    }
+
+    let mut urls = vec![];
+    for (name, source) in config.remote_source.iter() {
+        urls.push((format!("remote-source '{}'", name), &source.base_url));
+        let item = format!("remote-source '{}' include/exclude", name);
+        report.check(&item, util::PathFilter::new(&source.include, &source.exclude), "valid");
+    }
+    for (name, source) in config.oci_source.iter() {
+        urls.push((format!("oci-source '{}'", name), &source.registry_url));
+    }
+    for (name, source) in config.s3_source.iter() {
+        urls.push((format!("s3-source '{}'", name), &source.endpoint));
+    }
+    for (name, source) in config.gitea_source.iter() {
+        urls.push((format!("gitea-source '{}'", name), &source.base_url));
+    }
+    for (name, source) in config.jenkins_source.iter() {
+        urls.push((format!("jenkins-source '{}'", name), &source.base_url));
+    }
+    for (name, source) in config.speardrive_source.iter() {
+        urls.push((format!("speardrive-source '{}'", name), &source.base_url));
+    }
+    for (item, url) in urls {
+        report.check(&item, check_url(url), url);
+    }
+}
+
+/// The checks a running server is ready by: the caches are writable and the
+/// tools it can't do without are installed. Returns the failed checks.
+pub fn readiness(config: &Config) -> Vec<String> {
+    let mut failures = vec![];
+
+    for dir in [&config.composites_cache, &config.local_cache] {
+        if let Err(err) = check_writable(dir) {
+            failures.push(format!("{}: {}", dir.display(), err));
+        }
+    }
+    for (tool, required, _) in TOOLS {
+        if *required && find_tool(tool).is_none() {
+            failures.push(format!("tool '{}' not found", tool));
+        }
+    }
+
+    failures
+}
+
+/// Validate a configuration before using it, returning the failed checks.
+pub fn validate(config: &Config) -> Result<(), Vec<String>> {
+    let mut report = Report {
+        quiet: true,
+        ..Default::default()
+    };
+    check_config(config, &mut report);
+
+    if report.failures.is_empty() {
+        Ok(())
+    } else {
+        Err(report.failures)
+    }
+}
+
+/// Validate the configuration and the environment, reporting each item.
+/// With `--online`, the Gitlab credentials are also tried.
+pub async fn run(config: &Config, args: &CheckConfigArgs) -> Result<(), Error> {
+    let mut report = Report::default();
+    check_config(config, &mut report);
+
+    let mut clients = ClientCache::new();
+    for (name, source) in config.gitlabs.iter() {
+        let item = format!("gitlab '{}'", name);
+        if source.hostname.is_empty() || source.hostname.contains('/') {
+            report.fail(&item, format!("'{}' is not a hostname", source.hostname));
+            continue;
+        }
+        if !args.online {
+            report.ok(&item, &source.hostname);
+            continue;
+        }
+
+        let client = match clients.get(name, source).await {
+            Ok(client) => client,
+            Err(err) => {
+                report.fail(&item, err);
+                continue;
+            }
+        };
+        let endpoint = match gitlab::api::users::CurrentUser::builder().build() {
+            Ok(endpoint) => endpoint,
+            Err(err) => {
+                report.fail(&item, err);
+                continue;
+            }
+        };
+        let user: Result<serde_json::Value, _> = endpoint.query_async(client).await;
+        match user {
+            Ok(user) => report.ok(
+                &item,
+                format!(
+                    "authenticated to {} as {}",
+                    source.hostname,
+                    user["username"].as_str().unwrap_or("?")
+                ),
+            ),
+            Err(err) => report.fail(&item, err),
+        }
+    }
+
+    for (tool, required, purpose) in TOOLS {
+        let item = format!("tool '{}'", tool);
+        match find_tool(tool) {
+            Some(path) => report.ok(&item, path.display()),
+            None if *required => report.fail(&item, format!("not found, needed for {}", purpose)),
+            None => report.warn(&item, format!("not found, needed for {}", purpose)),
+        }
+    }
+
+    println!("{} failed, {} warnings", report.failures.len(), report.warnings);
+    if !report.failures.is_empty() {
+        return Err(Error::ConfigCheck(report.failures.len()));
+    }
+
+    Ok(())
+}
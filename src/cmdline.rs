@@ -20,4 +20,59 @@ pub struct CommandArgs {
 pub enum Command {
     ExampleConf,
     Serve,
+    Gc(GcArgs),
+    Build(BuildArgs),
+    Plan(PlanArgs),
+    CheckConfig(CheckConfigArgs),
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct CheckConfigArgs {
+    #[structopt(help = "Also authenticate to the Gitlab servers", long = "online")]
+    pub online: bool,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct PlanArgs {
+    #[structopt(help = "The plan, as in the path of a URL, e.g. /myserver/foo/323/-/rpm")]
+    pub plan: String,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct BuildArgs {
+    #[structopt(help = "The plan, as in the path of a URL, e.g. /myserver/foo/323/-/rpm")]
+    pub plan: String,
+
+    #[structopt(
+        help = "Where to place the repo, or the archive for plans ending with an archive suffix",
+        long = "out",
+        short = "o"
+    )]
+    pub out: PathBuf,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct GcArgs {
+    #[structopt(help = "Report what would be removed without removing it", long = "dry-run")]
+    pub dry_run: bool,
+
+    #[structopt(
+        help = "Evict entries older than this age (e.g. 90m, 12h, 30d)",
+        long = "max-age",
+        parse(try_from_str = crate::gc::parse_age)
+    )]
+    pub max_age: Option<std::time::Duration>,
+
+    #[structopt(
+        help = "Evict the oldest entries until each cache fits this size (e.g. 500M, 20G)",
+        long = "max-size",
+        parse(try_from_str = crate::gc::parse_size)
+    )]
+    pub max_size: Option<u64>,
+
+    #[structopt(
+        help = "Evict cached artifacts that no composite uses",
+        long = "unreferenced"
+    )]
+    pub unreferenced: bool,
 }
@@ -1,6 +1,67 @@
 use std::{collections::BTreeMap, path::PathBuf};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::Error;
+
+/// A credential, which is redacted when the config is serialized or logged.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Read a secret from a file, e.g. a Docker or Kubernetes secret, or from
+    /// an environment variable, whichever is given.
+    pub fn load(
+        name: &str,
+        file: &Option<PathBuf>,
+        env: &Option<String>,
+    ) -> Result<Option<Secret>, Error> {
+        let secret = match (file, env) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Secret(
+                    name.to_owned(),
+                    "both a file and an env var given".to_owned(),
+                ))
+            }
+            (Some(file), None) => std::fs::read_to_string(file)
+                .map_err(|e| Error::Secret(name.to_owned(), format!("{}: {}", file.display(), e)))?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned(),
+            (None, Some(env)) => std::env::var(env)
+                .map_err(|e| Error::Secret(name.to_owned(), format!("{}: {}", env, e)))?,
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Secret(secret)))
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Secret(s.to_owned())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -9,6 +70,15 @@ pub struct Config {
     pub local_cache: PathBuf,
     pub listen_addr: String,
 
+    /// Seconds to let requests in flight finish on shutdown.
+    #[serde(default = "default_shutdown_deadline")]
+    pub shutdown_deadline: u64,
+
+    /// Timeouts and retries of upstream fetches, for the sources that don't
+    /// override them.
+    #[serde(default)]
+    pub http: HttpOptions,
+
     #[serde(default)]
     pub gitlabs: BTreeMap<String, GitlabJobSource>,
 
@@ -17,23 +87,280 @@ pub struct Config {
 
     #[serde(default)]
     pub local_source: BTreeMap<String, LocalPathSource>,
+
+    #[serde(default)]
+    pub oci_source: BTreeMap<String, OciSource>,
+
+    #[serde(default)]
+    pub s3_source: BTreeMap<String, S3Source>,
+
+    #[serde(default)]
+    pub gitea_source: BTreeMap<String, GiteaSource>,
+
+    #[serde(default)]
+    pub jenkins_source: BTreeMap<String, JenkinsSource>,
+
+    #[serde(default)]
+    pub speardrive_source: BTreeMap<String, SpeardriveSource>,
+}
+
+impl Config {
+    /// The default filter configured for the named source, if any.
+    pub fn source_filter(&self, name: &str) -> Option<&String> {
+        self.gitlabs
+            .get(name)
+            .map(|x| &x.filter)
+            .or_else(|| self.remote_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.local_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.oci_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.s3_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.gitea_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.jenkins_source.get(name).map(|x| &x.filter))
+            .or_else(|| self.speardrive_source.get(name).map(|x| &x.filter))
+            .and_then(|x| x.as_ref())
+    }
+
+    /// Fill the credentials given by `*-file` or `*-env` options.
+    pub fn load_secrets(&mut self) -> Result<(), Error> {
+        fn load(
+            value: &mut Option<Secret>,
+            name: String,
+            file: &Option<PathBuf>,
+            env: &Option<String>,
+        ) -> Result<(), Error> {
+            if let Some(secret) = Secret::load(&name, file, env)? {
+                if value.is_some() {
+                    return Err(Error::Secret(name, "given both directly and indirectly".to_owned()));
+                }
+                *value = Some(secret);
+            }
+            Ok(())
+        }
+
+        for (name, x) in self.gitlabs.iter_mut() {
+            let name = format!("gitlabs.{}.api-key", name);
+            let mut api_key = Some(std::mem::take(&mut x.api_key)).filter(|x| !x.is_empty());
+            load(&mut api_key, name.clone(), &x.api_key_file, &x.api_key_env)?;
+            x.api_key = api_key.ok_or_else(|| Error::Secret(name, "missing".to_owned()))?;
+        }
+        for (name, x) in self.gitea_source.iter_mut() {
+            let name = format!("gitea-source.{}.token", name);
+            load(&mut x.token, name, &x.token_file, &x.token_env)?;
+        }
+        for (name, x) in self.jenkins_source.iter_mut() {
+            let name = format!("jenkins-source.{}.api-token", name);
+            load(&mut x.api_token, name, &x.api_token_file, &x.api_token_env)?;
+        }
+        for (name, x) in self.oci_source.iter_mut() {
+            let name = format!("oci-source.{}.password", name);
+            load(&mut x.password, name, &x.password_file, &x.password_env)?;
+        }
+        for (name, x) in self.s3_source.iter_mut() {
+            let name = format!("s3-source.{}.secret-key", name);
+            load(&mut x.secret_key, name, &x.secret_key_file, &x.secret_key_env)?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct GitlabJobSource {
-    pub api_key: String,
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    pub api_key: Secret,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+
     pub hostname: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpOptions>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GiteaSource {
+    pub base_url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JenkinsSource {
+    pub base_url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token: Option<Secret>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token_env: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RemoteSource {
     pub base_url: String,
+
+    #[serde(default)]
+    pub mode: RemoteMode,
+
+    /// Glob patterns of files to download, all files if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
+    /// Glob patterns of files not to download.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// How many directory levels to descend when crawling, 16 if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpOptions>,
+}
+
+/// Timeouts and retries of HTTP fetches, each falling back to the global
+/// option, then to the built-in default, when unset.
+#[derive(Debug, Deserialize, Clone, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct HttpOptions {
+    /// Seconds to wait for a connection to be established.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds to wait for the response headers, and for each read of the
+    /// body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+
+    /// How many times to retry a fetch failing with a transient error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+
+    /// Milliseconds to wait before the first retry, doubling for each one
+    /// after it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,
+}
+
+/// How the files of a remote directory are discovered.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteMode {
+    /// Use the `list.txt` under the directory.
+    #[default]
+    ListTxt,
+
+    /// Crawl the HTML or JSON index pages generated by the web server.
+    Autoindex,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SpeardriveSource {
+    pub base_url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct LocalPathSource {
     pub root: PathBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OciSource {
+    pub registry_url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Source {
+    pub endpoint: String,
+    pub bucket: String,
+
+    #[serde(default)]
+    pub prefix: String,
+
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<Secret>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key_env: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<HttpOptions>,
+}
+
+fn default_shutdown_deadline() -> u64 {
+    60
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
 }
@@ -0,0 +1,142 @@
+use std::collections::{HashSet, VecDeque};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{error::Error, http, util};
+
+/// How many directory levels to descend when crawling, unless configured.
+pub const DEFAULT_MAX_DEPTH: u32 = 16;
+
+/// How many index pages a crawl may fetch before giving up, as symlinks on
+/// the server can make the directory tree endless.
+const MAX_PAGES: usize = 10000;
+
+/// An entry of a JSON directory index, as generated by nginx's
+/// `autoindex_format json` or by Caddy's `browse`.
+#[derive(Debug, Deserialize)]
+struct JsonEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    is_dir: Option<bool>,
+}
+
+impl JsonEntry {
+    fn is_dir(&self) -> bool {
+        self.is_dir.unwrap_or(false) || self.kind.as_deref() == Some("directory")
+    }
+}
+
+/// Parse an index page into the names of its entries, and whether each is a
+/// directory. Links leading outside the directory are ignored.
+fn parse_index(content_type: &str, body: &str) -> Result<Vec<(String, bool)>, Error> {
+    lazy_static::lazy_static! {
+        static ref HREF_RE: Regex = Regex::new(r#"(?i)href\s*=\s*["']([^"']+)["']"#).unwrap();
+    }
+
+    if content_type.contains("json") {
+        let entries: Vec<JsonEntry> = serde_json::from_str(body)?;
+        return Ok(entries
+            .into_iter()
+            .map(|x| {
+                let is_dir = x.is_dir();
+                (x.name.trim_end_matches('/').to_owned(), is_dir)
+            })
+            .collect());
+    }
+
+    let mut entries = vec![];
+    for cap in HREF_RE.captures_iter(body) {
+        let href = cap[1].replace("&amp;", "&");
+        let href = href.split(['?', '#']).next().unwrap_or("");
+        let href = href.strip_prefix("./").unwrap_or(href);
+        if href.is_empty() || href.starts_with('/') || href.contains("://") {
+            continue;
+        }
+
+        let (name, is_dir) = match href.strip_suffix('/') {
+            Some(name) => (name, true),
+            None => (href, false),
+        };
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+
+        entries.push((util::percent_decode(name), is_dir));
+    }
+
+    Ok(entries)
+}
+
+/// Discover the files under `base_url` by recursively following its directory
+/// index pages, returning the relative path and URL of each file. Directories
+/// deeper than `max_depth`, or `DEFAULT_MAX_DEPTH` if unset, are skipped.
+pub async fn list_files(
+    base_url: &str,
+    max_depth: Option<u32>,
+    policy: &http::Policy,
+) -> Result<Vec<(String, String)>, Error> {
+    let base_url = base_url.trim_end_matches('/');
+    let client = http::client_for(policy);
+    let max_depth = max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((String::new(), 0));
+    let mut pages = 0;
+
+    while let Some((dir, depth)) = queue.pop_front() {
+        pages += 1;
+        if pages > MAX_PAGES {
+            return Err(Error::InvalidResponse(
+                base_url.to_owned(),
+                format!("more than {} index pages", MAX_PAGES),
+            ));
+        }
+
+        let dir_url = if dir.is_empty() {
+            format!("{}/", base_url)
+        } else {
+            format!("{}/{}/", base_url, dir)
+        };
+
+        let rsp = http::send_with(policy, client.get(&dir_url)).await?;
+        let content_type = rsp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("")
+            .to_owned();
+        let body = http::read_body(policy, rsp).await?;
+        let body = String::from_utf8_lossy(&body);
+
+        for (name, is_dir) in parse_index(&content_type, &body)? {
+            if name == "." || name == ".." || name.contains('/') {
+                continue;
+            }
+
+            let encoded = util::percent_encode(&name);
+            let (rel, rel_url) = if dir.is_empty() {
+                (name.clone(), encoded)
+            } else {
+                (format!("{}/{}", util::percent_decode(&dir), name), format!("{}/{}", dir, encoded))
+            };
+
+            if !seen.insert(rel_url.clone()) {
+                continue;
+            }
+
+            if is_dir {
+                if depth < max_depth {
+                    queue.push_back((rel_url, depth + 1));
+                }
+            } else {
+                files.push((rel, format!("{}/{}", base_url, rel_url)));
+            }
+        }
+    }
+
+    Ok(files)
+}
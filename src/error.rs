@@ -3,6 +3,7 @@ use std::{num::ParseIntError, sync::Arc};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Io error: {0}")]
     IoError(#[from] std::io::Error),
@@ -19,6 +20,9 @@ pub enum Error {
     #[error("YAML error: {0}")]
     YAMLError(#[from] serde_yaml::Error),
 
+    #[error("JSON error: {0}")]
+    JSONError(#[from] serde_json::Error),
+
     #[error("ParseIntError error: {0}")]
     ParseIntError(#[from] ParseIntError),
 
@@ -43,6 +47,15 @@ pub enum Error {
     #[error("Unknown source: {0}")]
     UnknownSource(String),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Not ready yet: {0}")]
+    NotReady(String),
+
     #[error("{0}")]
     Boxed(Arc<dyn std::error::Error + Send + Sync + 'static>),
 
@@ -54,4 +67,22 @@ pub enum Error {
 
     #[error("Reqwest: {0}")]
     Reqwest(#[from] reqwest::Error),
+
+    #[error("Error downloading {0}: {1}")]
+    HttpStatus(String, reqwest::StatusCode),
+
+    #[error("Timed out fetching {0}")]
+    Timeout(String),
+
+    #[error("Glob pattern error: {0}")]
+    Glob(#[from] globset::Error),
+
+    #[error("Error loading secret {0}: {1}")]
+    Secret(String, String),
+
+    #[error("{0} config checks failed")]
+    ConfigCheck(usize),
+
+    #[error("Invalid response from {0}: {1}")]
+    InvalidResponse(String, String),
 }
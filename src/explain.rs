@@ -0,0 +1,131 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::{config::Config, error::Error, Artifact, Kind, Plan};
+
+/// Path prefix under which the server explains a plan instead of serving it.
+pub const EXPLAIN_PREFIX: &str = "/_explain/";
+
+/// Whether the artifact's version is only known once its source is queried,
+/// as with tags and permalinks.
+fn resolved_on_request(artifact: &Artifact) -> bool {
+    match artifact {
+        Artifact::Oci(oa) => oa.digest.is_none(),
+        Artifact::S3(_) => true,
+        Artifact::JenkinsBuild(jba) => jba.build_number.is_none(),
+        Artifact::Peer(_) => true,
+        _ => false,
+    }
+}
+
+/// Describe how a plan is parsed and what serving it would do, without
+/// downloading or building anything.
+pub fn explain(config: &Arc<Config>, path: &str, query: Option<&str>) -> Result<String, Error> {
+    let plan = Plan::from_uri(path, query, config)?;
+    let mut out = String::new();
+
+    let kind = match &plan.kind {
+        Kind::RPM => "rpm".to_owned(),
+        Kind::Pacman(name) => format!("pacman repo '{}'", name),
+        Kind::Files => "files".to_owned(),
+    };
+    let _ = writeln!(out, "plan: {}", path);
+    let _ = writeln!(out, "kind: {}", kind);
+    if !plan.rpm.is_default() {
+        let _ = writeln!(out, "rpm options: {:?}", plan.rpm);
+    }
+    if let Some(format) = plan.archive {
+        let _ = writeln!(out, "archive: {:?}", format);
+    }
+    let _ = writeln!(out, "sub-uri: {}", plan.sub_uri);
+
+    let mut downloads = 0;
+    let mut unresolved = false;
+    let _ = writeln!(out, "\nartifacts:");
+    for (idx, artifact) in plan.artifacts.iter().enumerate() {
+        let _ = writeln!(out, "  {}: {}", idx, plan.specs[idx]);
+        let _ = writeln!(out, "     artifact: {:?}", artifact);
+        if let Some(filter) = &plan.filters[idx] {
+            let _ = writeln!(out, "     filter: {}", filter);
+        }
+
+        let local_path = artifact.local_path(config);
+        let state = match (&local_path, artifact) {
+            (Some(path), Artifact::Local(_)) if path.exists() => "local directory",
+            (Some(_), Artifact::Local(_)) => "local directory, missing",
+            (Some(path), _) if path.exists() => "cached",
+            _ if resolved_on_request(artifact) => {
+                unresolved = true;
+                downloads += 1;
+                "resolved on request, cached if unchanged"
+            }
+            _ => {
+                downloads += 1;
+                "not cached, to be downloaded"
+            }
+        };
+        match &local_path {
+            Some(path) => {
+                let _ = writeln!(out, "     cache path: {} ({})", path.display(), state);
+            }
+            None => {
+                let _ = writeln!(out, "     cache path: ({})", state);
+            }
+        }
+    }
+
+    let node_name = plan.to_composite_path();
+    let composite_path = config.composites_cache.join(&node_name);
+    let composite_exists = !unresolved && composite_path.exists();
+    let _ = writeln!(out, "\ncomposite:");
+    if unresolved {
+        let _ = writeln!(
+            out,
+            "  hash: depends on the artifacts resolved on request ({} before resolving)",
+            node_name
+        );
+    } else {
+        let _ = writeln!(out, "  hash: {}", node_name);
+        let _ = writeln!(
+            out,
+            "  path: {} ({})",
+            composite_path.display(),
+            if composite_exists { "exists" } else { "to be built" }
+        );
+    }
+
+    let _ = writeln!(out, "\nactions:");
+    if downloads > 0 {
+        let _ = writeln!(out, "  - resolve and download {} artifacts", downloads);
+    }
+    if composite_exists {
+        let _ = writeln!(out, "  - use the existing composite");
+    } else {
+        let _ = writeln!(out, "  - place the files of {} artifacts", plan.artifacts.len());
+        match &plan.kind {
+            Kind::RPM => {
+                if !plan.rpm.is_default() {
+                    let _ = writeln!(out, "  - filter the packages by their headers");
+                }
+                let _ = writeln!(out, "  - index with createrepo");
+            }
+            Kind::Pacman(name) => {
+                let _ = writeln!(out, "  - create the '{}' database with repo-add", name);
+            }
+            Kind::Files => {
+                let _ = writeln!(out, "  - write SHA256SUMS");
+            }
+        }
+        let _ = writeln!(out, "  - write manifest.json");
+    }
+    match plan.archive {
+        Some(format) => {
+            let _ = writeln!(out, "  - stream the composite as {:?}", format);
+        }
+        None => {
+            let _ = writeln!(out, "  - serve {}", plan.sub_uri);
+        }
+    }
+
+    Ok(out)
+}
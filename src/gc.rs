@@ -0,0 +1,505 @@
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use fs2::FileExt;
+
+use crate::{cmdline::GcArgs, config::Config, error::Error, manifest, util, Plan};
+
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().map_err(|_| format!("{} is not an age", s))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" | "" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("{} unknown age unit, expected s, m, h or d", unit)),
+    };
+    Ok(Duration::from_secs(num * secs))
+}
+
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().map_err(|_| format!("{} is not a size", s))?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("{} unknown size unit, expected K, M, G or T", unit)),
+    };
+    Ok(num << shift)
+}
+
+/// A cached artifact or a composite, or the leftovers of a build, along with
+/// the lock file its cache takes while building it.
+struct Entry {
+    path: PathBuf,
+    /// Files that go along with the entry, e.g. its markers.
+    extra: Vec<PathBuf>,
+    lock: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+impl Entry {
+    fn new(
+        path: PathBuf,
+        extra: Vec<PathBuf>,
+        lock: PathBuf,
+        measure: bool,
+    ) -> Result<Self, Error> {
+        let (modified, size) = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => (
+                metadata.modified()?,
+                if measure {
+                    disk_usage(&path, &mut HashSet::new())?
+                } else {
+                    0
+                },
+            ),
+            // A build may be interrupted before it creates its directory.
+            Err(_) => (SystemTime::UNIX_EPOCH, 0),
+        };
+        Ok(Self {
+            path,
+            extra,
+            lock,
+            modified,
+            size,
+        })
+    }
+}
+
+/// Total size of the files under `path`, counting hardlinked files once.
+fn disk_usage(path: &Path, inodes: &mut HashSet<(u64, u64)>) -> Result<u64, Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(if inodes.insert((metadata.dev(), metadata.ino())) {
+            metadata.len()
+        } else {
+            0
+        });
+    }
+
+    let mut size = 0;
+    for child in std::fs::read_dir(path)? {
+        size += disk_usage(&child?.path(), inodes)?;
+    }
+    Ok(size)
+}
+
+/// Total size of a cache, counting hardlinked files once.
+pub fn cache_size(root: &Path) -> Result<u64, Error> {
+    if !root.exists() {
+        return Ok(0);
+    }
+    disk_usage(root, &mut HashSet::new())
+}
+
+/// The lock file taken while building in `dir`, being the nearest one up to
+/// the root of the cache.
+fn find_lock(root: &Path, dir: &Path) -> PathBuf {
+    dir.ancestors()
+        .take_while(|x| x.starts_with(root))
+        .map(|x| x.join("lock"))
+        .find(|x| x.is_file())
+        .unwrap_or_else(|| root.join("lock"))
+}
+
+/// Whether anything under `dir` belongs to the cache rather than to an
+/// artifact: lock files, or the markers of entries and builds.
+fn holds_cache_files(dir: &Path) -> Result<bool, Error> {
+    for child in std::fs::read_dir(dir)? {
+        let child = child?;
+        let name = child.file_name().to_string_lossy().into_owned();
+        let marker = name.ends_with(util::ENTRY_MARKER) || name.ends_with(util::BUILD_MARKER);
+        if name == "lock" || marker {
+            return Ok(true);
+        }
+        if child.file_type()?.is_dir() && holds_cache_files(&child.path())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Find the entries of the local cache, and the leftovers of its interrupted
+/// builds, by the markers their builds write next to them. Entries are not
+/// descended into, so the contents of artifacts are never taken for entries.
+/// Entries cached before the markers were written are the unmarked
+/// directories next to the empty lock file of their cache, holding nothing of
+/// the cache themselves.
+fn scan_local(
+    root: &Path,
+    dir: &Path,
+    measure: bool,
+    entries: &mut Vec<Entry>,
+    tmps: &mut Vec<Entry>,
+) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for child in std::fs::read_dir(dir)? {
+        names.insert(child?.file_name().to_string_lossy().into_owned());
+    }
+    let mut names: Vec<_> = names.into_iter().collect();
+    names.sort();
+
+    let is_marked = |name: &str| {
+        names.contains(&format!("{}{}", name, util::ENTRY_MARKER))
+            || names.contains(&format!("{}{}", name, util::BUILD_MARKER))
+    };
+    let is_cache_level = names.iter().any(|x| x == "lock")
+        && std::fs::metadata(dir.join("lock")).is_ok_and(|x| x.is_file() && x.len() == 0);
+    let is_leftover = |name: &str| {
+        util::TMP_SUFFIXES.iter().any(|suffix| {
+            name.strip_suffix(suffix)
+                .is_some_and(|base| names.contains(&format!("{}{}", base, util::BUILD_MARKER)))
+        })
+    };
+
+    for name in names.iter() {
+        let path = dir.join(name);
+        if let Some(base) = name.strip_suffix(util::BUILD_MARKER) {
+            let base = dir.join(base);
+            let mut extra: Vec<_> = util::TMP_SUFFIXES[1..]
+                .iter()
+                .map(|suffix| util::with_suffix(&base, suffix))
+                .collect();
+            extra.push(path.clone());
+            let tmp = util::with_suffix(&base, util::TMP_SUFFIXES[0]);
+            tmps.push(Entry::new(tmp, extra, find_lock(root, dir), measure)?);
+        } else if let Some(base) = name.strip_suffix(util::ENTRY_MARKER) {
+            let base = dir.join(base);
+            if base.is_dir() {
+                // Metadata saved next to the entry, e.g. Gitlab jobs.
+                let extra = vec![path.clone(), util::with_suffix(&base, ".json")];
+                entries.push(Entry::new(base, extra, find_lock(root, dir), measure)?);
+            }
+        } else if !is_marked(name) && !is_leftover(name) && path.is_dir() {
+            if is_cache_level && !holds_cache_files(&path)? {
+                let extra = vec![util::with_suffix(&path, ".json")];
+                entries.push(Entry::new(path, extra, dir.join("lock"), measure)?);
+            } else {
+                scan_local(root, &path, measure, entries, tmps)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the composites, being the directories at the root of the composites
+/// cache, and the `.tmp` directories of their interrupted builds.
+fn scan_composites(root: &Path, measure: bool) -> Result<(Vec<Entry>, Vec<Entry>), Error> {
+    let mut entries = vec![];
+    let mut tmps = vec![];
+    if !root.is_dir() {
+        return Ok((entries, tmps));
+    }
+
+    for child in std::fs::read_dir(root)? {
+        let child = child?;
+        if !child.file_type()?.is_dir() {
+            continue;
+        }
+        let path = child.path();
+        let entry = Entry::new(path.clone(), vec![], root.join("lock"), measure)?;
+        if path.extension().is_some_and(|x| x == "tmp") {
+            tmps.push(entry);
+        } else {
+            entries.push(entry);
+        }
+    }
+
+    entries.sort_by_key(|x| x.modified);
+    Ok((entries, tmps))
+}
+
+fn scan_cache(root: &Path, measure: bool) -> Result<(Vec<Entry>, Vec<Entry>), Error> {
+    let mut entries = vec![];
+    let mut tmps = vec![];
+    if root.is_dir() {
+        scan_local(root, root, measure, &mut entries, &mut tmps)?;
+    }
+    entries.sort_by_key(|x| x.modified);
+    Ok((entries, tmps))
+}
+
+/// The cache entries used by a composite, from its manifest, or by parsing
+/// its `url.txt` for composites that predate manifests.
+fn composite_references(config: &Arc<Config>, composite: &Path) -> Vec<PathBuf> {
+    if let Ok(content) = std::fs::read(composite.join(manifest::MANIFEST_FILE)) {
+        if let Ok(manifest) = serde_json::from_slice::<manifest::Manifest>(&content) {
+            return manifest.sources.into_iter().filter_map(|x| x.cache_path).collect();
+        }
+    }
+
+    let url = match std::fs::read_to_string(composite.join("url.txt")) {
+        Ok(url) => url,
+        Err(_) => return vec![],
+    };
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url.as_str(), None),
+    };
+    match Plan::from_uri(path, query, config) {
+        Ok(plan) => plan.artifacts.iter().filter_map(|x| x.local_path(config)).collect(),
+        Err(_) => vec![],
+    }
+}
+
+struct Collector<'a> {
+    args: &'a GcArgs,
+    removed: usize,
+    freed: u64,
+}
+
+impl<'a> Collector<'a> {
+    /// Remove an entry, unless a build holds the lock of its cache or a
+    /// request holds the entry in use. Returns whether the entry is gone.
+    fn remove(&mut self, entry: &Entry, reason: &str) -> Result<bool, Error> {
+        if self.args.dry_run {
+            println!("would remove {} ({}, {} bytes)", entry.path.display(), reason, entry.size);
+        } else {
+            let lockfile = std::fs::File::create(&entry.lock)?;
+            if lockfile.try_lock_exclusive().is_err() {
+                println!("skipping {}: cache busy", entry.path.display());
+                return Ok(false);
+            }
+
+            // Requests take shared locks on the directories they use, see
+            // `util::InUse`.
+            let dir = std::fs::File::open(&entry.path).ok();
+            if dir.as_ref().is_some_and(|x| x.try_lock_exclusive().is_err()) {
+                println!("skipping {}: in use", entry.path.display());
+                return Ok(false);
+            }
+
+            remove_entry(entry)?;
+
+            println!("removed {} ({}, {} bytes)", entry.path.display(), reason, entry.size);
+        }
+
+        self.removed += 1;
+        self.freed += entry.size;
+        Ok(true)
+    }
+
+    /// Evict the entries by age, then the oldest ones until the cache fits
+    /// the maximum size. Returns the remaining entries.
+    fn evict(&mut self, entries: Vec<Entry>) -> Result<Vec<Entry>, Error> {
+        let now = SystemTime::now();
+        let mut kept = vec![];
+
+        for entry in entries {
+            let age = now.duration_since(entry.modified).unwrap_or_default();
+            let expired = match self.args.max_age {
+                Some(max_age) => age > max_age,
+                None => false,
+            };
+            if !(expired && self.remove(&entry, "expired")?) {
+                kept.push(entry);
+            }
+        }
+
+        if let Some(max_size) = self.args.max_size {
+            let mut total: u64 = kept.iter().map(|x| x.size).sum();
+            let mut remaining = vec![];
+            for entry in kept {
+                if total > max_size && self.remove(&entry, "over size")? {
+                    total -= entry.size;
+                } else {
+                    remaining.push(entry);
+                }
+            }
+            kept = remaining;
+        }
+
+        Ok(kept)
+    }
+}
+
+/// Remove an entry along with its extra files, some of which may not exist.
+fn remove_entry(entry: &Entry) -> Result<(), Error> {
+    match std::fs::remove_dir_all(&entry.path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    for extra in entry.extra.iter() {
+        let _ = std::fs::remove_file(extra);
+    }
+    Ok(())
+}
+
+/// Remove the `.tmp` directories left in both caches by builds that were
+/// interrupted, skipping the ones whose builds are still running. Returns
+/// the number of directories removed.
+pub fn remove_stale_tmps(config: &Config) -> Result<usize, Error> {
+    let mut removed = 0;
+
+    let (_, composite_tmps) = scan_composites(&config.composites_cache, false)?;
+    let (_, artifact_tmps) = scan_cache(&config.local_cache, false)?;
+    for entry in composite_tmps.into_iter().chain(artifact_tmps) {
+        let lockfile = std::fs::File::create(&entry.lock)?;
+        if lockfile.try_lock_exclusive().is_err() {
+            continue;
+        }
+
+        log::info!("removing stale {}", entry.path.display());
+        remove_entry(&entry)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Clean up both caches: remove the leftovers of interrupted builds, then
+/// evict composites, then evict cached artifacts. Takes the same lock files
+/// as the server, so it is safe to run alongside it.
+pub fn run(config: &Config, args: &GcArgs) -> Result<(), Error> {
+    let config = Arc::new(config.clone());
+    let mut collector = Collector {
+        args,
+        removed: 0,
+        freed: 0,
+    };
+
+    let (composites, composite_tmps) = scan_composites(&config.composites_cache, true)?;
+    let (artifacts, artifact_tmps) = scan_cache(&config.local_cache, true)?;
+
+    for entry in composite_tmps.iter().chain(artifact_tmps.iter()) {
+        collector.remove(entry, "stale build")?;
+    }
+
+    let composites = collector.evict(composites)?;
+
+    let artifacts = if args.unreferenced {
+        let referenced: Vec<_> = composites
+            .iter()
+            .flat_map(|x| composite_references(&config, &x.path))
+            .collect();
+
+        let mut kept = vec![];
+        for entry in artifacts {
+            let used = referenced
+                .iter()
+                .any(|x| x.starts_with(&entry.path) || entry.path.starts_with(x));
+            if used || !collector.remove(&entry, "unreferenced")? {
+                kept.push(entry);
+            }
+        }
+        kept
+    } else {
+        artifacts
+    };
+
+    collector.evict(artifacts)?;
+
+    println!(
+        "{} {} entries, {} bytes",
+        if args.dry_run { "would remove" } else { "removed" },
+        collector.removed,
+        collector.freed
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let name = format!("speardrive-gc-{}-{}", name, std::process::id());
+        let root = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn entries_by_markers() {
+        let root = temp_root("markers");
+        let project = root.join("gl/group/proj");
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::write(project.join("lock"), b"").unwrap();
+
+        // An artifact whose contents look like a cache of its own.
+        let entry = project.join("12");
+        std::fs::create_dir_all(entry.join("sub/old.tmp")).unwrap();
+        std::fs::write(entry.join("lock"), b"").unwrap();
+        std::fs::write(entry.join("sub/lock"), b"").unwrap();
+        std::fs::write(entry.join("sub/x.building"), b"").unwrap();
+        std::fs::write(project.join("12.cached"), b"").unwrap();
+
+        // An interrupted build, and an entry cached by an older version.
+        std::fs::create_dir_all(project.join("13.tmp")).unwrap();
+        std::fs::write(project.join("13.building"), b"").unwrap();
+        std::fs::create_dir_all(project.join("11/sub")).unwrap();
+
+        let (entries, tmps) = scan_cache(&root, false).unwrap();
+        let mut paths: Vec<_> = entries.iter().map(|x| x.path.clone()).collect();
+        paths.sort();
+        assert_eq!(paths, vec![project.join("11"), entry]);
+        assert!(entries.iter().all(|x| x.lock == project.join("lock")));
+        let paths: Vec<_> = tmps.iter().map(|x| x.path.clone()).collect();
+        assert_eq!(paths, vec![project.join("13.tmp")]);
+        assert!(tmps[0].extra.contains(&project.join("13.tar.tmp")));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn entries_in_use_are_kept() {
+        let root = temp_root("in-use");
+        std::fs::write(root.join("lock"), b"").unwrap();
+        std::fs::create_dir_all(root.join("12")).unwrap();
+        std::fs::write(root.join("12.cached"), b"").unwrap();
+
+        let args = GcArgs {
+            dry_run: false,
+            max_age: None,
+            max_size: None,
+            unreferenced: false,
+        };
+        let mut collector = Collector {
+            args: &args,
+            removed: 0,
+            freed: 0,
+        };
+        let (entries, _) = scan_cache(&root, false).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let mut in_use = util::InUse::default();
+        assert!(in_use.add(&root.join("12")).unwrap());
+        assert!(!collector.remove(&entries[0], "test").unwrap());
+        assert!(root.join("12").is_dir());
+
+        drop(in_use);
+        assert!(collector.remove(&entries[0], "test").unwrap());
+        assert!(!root.join("12").exists());
+        assert!(!root.join("12.cached").exists());
+
+        let mut in_use = util::InUse::default();
+        assert!(!in_use.add(&root.join("12")).unwrap());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("2"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert!(parse_age("3w").is_err());
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("20g"), Ok(20 << 30));
+        assert!(parse_size("G").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use regex::Regex;
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+
+use crate::{config::GiteaSource, error::Error, http, util};
+
+#[derive(Debug, Clone)]
+pub struct GiteaRunArtifact {
+    pub source_name: String,
+    pub owner: String,
+    pub repo: String,
+    pub run_id: u64,
+}
+
+impl GiteaRunArtifact {
+    pub fn new(source_name: &str, owner: &str, repo: &str, run_id: &str) -> Result<Self, Error> {
+        lazy_static::lazy_static! {
+            static ref NAME_RE: Regex = Regex::new("^[A-Za-z0-9_.-]+$").unwrap();
+        }
+
+        for name in [owner, repo] {
+            if !NAME_RE.is_match(name) {
+                return Err(Error::PlanParse(format!("{} invalid owner or repo name", name)));
+            }
+        }
+
+        Ok(Self {
+            source_name: source_name.to_owned(),
+            owner: owner.to_owned(),
+            repo: repo.to_owned(),
+            run_id: run_id.parse()?,
+        })
+    }
+
+    pub fn repo_path(&self, local_cache: &Path) -> std::path::PathBuf {
+        local_cache.join(&self.source_name).join(&self.owner).join(&self.repo)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RunArtifacts {
+    #[serde(default)]
+    artifacts: Vec<RunArtifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RunArtifact {
+    id: u64,
+    name: String,
+    #[serde(default)]
+    expired: bool,
+}
+
+fn authorize(req: RequestBuilder, source: &GiteaSource) -> RequestBuilder {
+    match &source.token {
+        Some(token) => req.header(reqwest::header::AUTHORIZATION, format!("token {}", token.expose())),
+        None => req,
+    }
+}
+
+/// Download and extract all the artifacts of an Actions run into
+/// `<local_cache>/<source>/<owner>/<repo>/<run>`.
+pub async fn cache_artifact(
+    local_cache: &Path,
+    run: &GiteaRunArtifact,
+    source: &GiteaSource,
+    uri: &str,
+) -> Result<(), Error> {
+    let repo_path = run.repo_path(local_cache);
+    let path = repo_path.join(format!("{}", run.run_id));
+    if path.exists() {
+        log::info!("request: {}: artifacts {} exist", uri, path.display());
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&repo_path)?;
+
+    let lockfile = std::fs::File::create(repo_path.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    let path_tmp = repo_path.join(format!("{}.tmp", run.run_id));
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    if let Err(err) = download_run(run, source, uri, &path_tmp).await {
+        util::abort_entry(&path_tmp, &path);
+        return Err(err);
+    }
+
+    log::info!("request: {}: placing artifacts", uri);
+    util::commit_entry(&path_tmp, &path)?;
+
+    Ok(())
+}
+
+/// Download the artifacts of the run and extract them together into
+/// `path_tmp`, refusing files that more than one artifact holds.
+async fn download_run(
+    run: &GiteaRunArtifact,
+    source: &GiteaSource,
+    uri: &str,
+    path_tmp: &Path,
+) -> Result<(), Error> {
+    log::info!(
+        "request: {}: querying repo '{}/{}' run '{}'",
+        uri,
+        run.owner,
+        run.repo,
+        run.run_id
+    );
+
+    let api = format!(
+        "{}/api/v1/repos/{}/{}/actions",
+        source.base_url.trim_end_matches('/'),
+        run.owner,
+        run.repo
+    );
+    let policy = http::policy(None);
+    let client = http::client_for(&policy);
+    let list_url = format!("{}/runs/{}/artifacts", api, run.run_id);
+    let body = http::fetch_with(&policy, authorize(client.get(&list_url), source)).await?;
+    let list: RunArtifacts = serde_json::from_slice(&body)?;
+
+    // Runs list no artifacts until they upload them, which mustn't be cached
+    // as the run having none.
+    if list.artifacts.is_empty() {
+        return Err(Error::NotFound(format!(
+            "artifacts of run {} of {}/{}",
+            run.run_id, run.owner, run.repo
+        )));
+    }
+
+    let mut owners: HashMap<PathBuf, &str> = HashMap::new();
+    for artifact in list.artifacts.iter() {
+        if artifact.expired {
+            return Err(Error::InvalidResponse(
+                list_url,
+                format!("artifact '{}' expired", artifact.name),
+            ));
+        }
+
+        log::info!("request: {}: downloading artifact '{}'", uri, artifact.name);
+
+        let zip_url = format!("{}/artifacts/{}/zip", api, artifact.id);
+        let artifact_zip = path_tmp.join(format!(".artifact-{}.zip", artifact.id));
+        let req = authorize(client.get(&zip_url), source);
+        http::download_with(&policy, req, &artifact_zip).await?;
+
+        log::info!("request: {}: extracting artifact '{}'", uri, artifact.name);
+        let extracted = path_tmp.join(format!(".artifact-{}", artifact.id));
+        {
+            let artifact_zip = artifact_zip.display();
+            let extracted = extracted.display();
+            util::bash(format!("unzip -q {artifact_zip} -d {extracted}"))?;
+        }
+        std::fs::remove_file(artifact_zip)?;
+
+        for rel in util::walk_files(&extracted)? {
+            if let Some(other) = owners.insert(rel.clone(), &artifact.name) {
+                return Err(Error::InvalidResponse(
+                    list_url,
+                    format!(
+                        "{} is in both artifacts '{}' and '{}'",
+                        rel.display(),
+                        other,
+                        artifact.name
+                    ),
+                ));
+            }
+
+            let target = path_tmp.join(&rel);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(extracted.join(&rel), target)?;
+        }
+        std::fs::remove_dir_all(extracted)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::Path,
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use tokio::io::AsyncWriteExt;
+
+use crate::{config::HttpOptions, error::Error};
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_READ_TIMEOUT: u64 = 60;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: u64 = 500;
+
+lazy_static::lazy_static! {
+    static ref GLOBAL: RwLock<HttpOptions> = RwLock::new(HttpOptions::default());
+    static ref CLIENTS: Mutex<HashMap<Duration, Client>> = Mutex::new(HashMap::new());
+}
+
+/// The timeouts and retries of the fetches from a source.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+/// Set the options of the sources that don't override them.
+pub fn configure(options: &HttpOptions) {
+    *GLOBAL.write().unwrap() = options.clone();
+}
+
+/// The policy of a source, from its own options, then the global ones.
+pub fn policy(source: Option<&HttpOptions>) -> Policy {
+    let global = GLOBAL.read().unwrap();
+    let pick = |f: fn(&HttpOptions) -> Option<u64>, default| {
+        source.and_then(f).or_else(|| f(&global)).unwrap_or(default)
+    };
+
+    Policy {
+        connect_timeout: Duration::from_secs(pick(|x| x.connect_timeout, DEFAULT_CONNECT_TIMEOUT)),
+        read_timeout: Duration::from_secs(pick(|x| x.read_timeout, DEFAULT_READ_TIMEOUT)),
+        retries: source
+            .and_then(|x| x.retries)
+            .or(global.retries)
+            .unwrap_or(DEFAULT_RETRIES),
+        backoff: Duration::from_millis(pick(|x| x.backoff, DEFAULT_BACKOFF)),
+    }
+}
+
+/// The shared HTTP client with the connect timeout of the policy.
+pub fn client_for(policy: &Policy) -> Client {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .entry(policy.connect_timeout)
+        .or_insert_with(|| {
+            Client::builder()
+                .connect_timeout(policy.connect_timeout)
+                .build()
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// Whether an error may go away by trying again.
+pub fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Reqwest(err) => {
+            err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+        }
+        Error::HttpStatus(_, status) => {
+            status.is_server_error()
+                || *status == StatusCode::REQUEST_TIMEOUT
+                || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        Error::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+/// Fail with a timeout if `fut` doesn't complete within the read timeout.
+pub async fn timeout<T>(
+    policy: &Policy,
+    what: &str,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::time::timeout(policy.read_timeout, fut)
+        .await
+        .map_err(|_| Error::Timeout(what.to_owned()))?
+}
+
+/// Run `attempt` until it succeeds, fails with an error that isn't
+/// transient, or runs out of retries, doubling the delay between attempts.
+pub async fn retry<T, F, Fut>(policy: &Policy, what: &str, mut attempt: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut retries = 0;
+    let mut delay = policy.backoff;
+
+    loop {
+        match attempt().await {
+            Err(err) if retries < policy.retries && is_transient(&err) => {
+                log::warn!("{}: {}, retrying in {:?}", what, err, delay);
+                tokio::time::sleep(delay).await;
+                retries += 1;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run `attempt` on the request, retrying it if the request is idempotent and
+/// can be repeated.
+async fn with_retries<T, F, Fut>(
+    policy: &Policy,
+    req: RequestBuilder,
+    attempt: F,
+) -> Result<T, Error>
+where
+    F: Fn(RequestBuilder, String) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let (url, idempotent) = match req.try_clone().map(|x| x.build()) {
+        Some(Ok(built)) => (built.url().to_string(), built.method().is_idempotent()),
+        _ => (String::new(), false),
+    };
+    if !idempotent {
+        return attempt(req, url).await;
+    }
+
+    let (req, url, attempt) = (&req, &url, &attempt);
+    retry(policy, url, || {
+        attempt(req.try_clone().unwrap(), url.clone())
+    })
+    .await
+}
+
+async fn send_once(policy: &Policy, req: RequestBuilder, url: &str) -> Result<Response, Error> {
+    let rsp = timeout(policy, url, async { Ok(req.send().await?) }).await?;
+    if !rsp.status().is_success() {
+        return Err(Error::HttpStatus(rsp.url().to_string(), rsp.status()));
+    }
+
+    Ok(rsp)
+}
+
+/// Read the next chunk of the body, failing if it takes longer than the read
+/// timeout.
+pub async fn chunk(policy: &Policy, rsp: &mut Response) -> Result<Option<Vec<u8>>, Error> {
+    let url = rsp.url().to_string();
+    let chunk = timeout(policy, &url, async { Ok(rsp.chunk().await?) }).await?;
+    Ok(chunk.map(|x| x.to_vec()))
+}
+
+/// Read the whole body, failing if any read takes longer than the read
+/// timeout.
+pub async fn read_body(policy: &Policy, mut rsp: Response) -> Result<Vec<u8>, Error> {
+    let mut body = vec![];
+    while let Some(chunk) = chunk(policy, &mut rsp).await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Send a request, turning an unsuccessful status into an error. Connection
+/// failures, timeouts and server errors are retried.
+pub async fn send_with(policy: &Policy, req: RequestBuilder) -> Result<Response, Error> {
+    with_retries(policy, req, |req, url| async move {
+        send_once(policy, req, &url).await
+    })
+    .await
+}
+
+/// Fetch the body of a request. Unlike with `send_with`, failures while
+/// reading the body are retried too.
+pub async fn fetch_with(policy: &Policy, req: RequestBuilder) -> Result<Vec<u8>, Error> {
+    with_retries(policy, req, |req, url| async move {
+        read_body(policy, send_once(policy, req, &url).await?).await
+    })
+    .await
+}
+
+/// Download the body of a request into a file, without holding it in memory.
+/// The file is rewritten from the start on each retry.
+pub async fn download_with(policy: &Policy, req: RequestBuilder, path: &Path) -> Result<(), Error> {
+    with_retries(policy, req, |req, url| async move {
+        let mut rsp = send_once(policy, req, &url).await?;
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = chunk(policy, &mut rsp).await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    })
+    .await
+}
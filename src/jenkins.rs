@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use regex::Regex;
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+
+use crate::{config::JenkinsSource, error::Error, http, util};
+
+/// Build permalinks that are resolved to a build number on each request.
+const PERMALINKS: &[&str] = &[
+    "lastBuild",
+    "lastCompletedBuild",
+    "lastStableBuild",
+    "lastSuccessfulBuild",
+];
+
+/// Directory of the builds of a job in the local cache, under the job's path.
+/// Job names can't hold an `@`, so it can't be taken for a job of a folder.
+const BUILDS_DIR: &str = "@builds";
+
+#[derive(Debug, Clone)]
+pub struct JenkinsBuildArtifact {
+    pub source_name: String,
+    pub job_path: String,
+    pub build: String,
+    /// The build number, given directly or resolved from a permalink.
+    pub build_number: Option<u64>,
+}
+
+impl JenkinsBuildArtifact {
+    pub fn new(source_name: &str, job_path: String, build: &str) -> Result<Self, Error> {
+        lazy_static::lazy_static! {
+            static ref JOB_RE: Regex = Regex::new("^[A-Za-z0-9_.-]+(/[A-Za-z0-9_.-]+)*$").unwrap();
+        }
+
+        if !JOB_RE.is_match(&job_path) {
+            return Err(Error::PlanParse(format!("{} invalid Jenkins job path", job_path)));
+        }
+
+        let build_number = if PERMALINKS.contains(&build) {
+            None
+        } else {
+            Some(build.parse()?)
+        };
+
+        Ok(Self {
+            source_name: source_name.to_owned(),
+            job_path,
+            build: build.to_owned(),
+            build_number,
+        })
+    }
+
+    pub fn builds_path(&self, local_cache: &Path) -> PathBuf {
+        local_cache.join(&self.source_name).join(&self.job_path).join(BUILDS_DIR)
+    }
+
+    /// The cache path of the build, once its number is known.
+    pub fn build_path(&self, local_cache: &Path) -> Option<PathBuf> {
+        let build_number = self.build_number?;
+        Some(self.builds_path(local_cache).join(format!("{}", build_number)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildStatus {
+    building: bool,
+}
+
+fn authorize(req: RequestBuilder, source: &JenkinsSource) -> RequestBuilder {
+    match &source.user {
+        Some(user) => req.basic_auth(user, source.api_token.as_ref().map(|x| x.expose())),
+        None => req,
+    }
+}
+
+/// Download the archived artifacts of a build into
+/// `<local_cache>/<source>/<job-path>/<build-number>`.
+pub async fn cache_artifact(
+    local_cache: &Path,
+    jba: &mut JenkinsBuildArtifact,
+    source: &JenkinsSource,
+    uri: &str,
+) -> Result<(), Error> {
+    let policy = http::policy(None);
+    let client = http::client_for(&policy);
+    let job_url = format!(
+        "{}/job/{}",
+        source.base_url.trim_end_matches('/'),
+        jba.job_path.split('/').collect::<Vec<_>>().join("/job/")
+    );
+
+    let build_number = match jba.build_number {
+        Some(build_number) => build_number,
+        None => {
+            let url = format!("{}/{}/buildNumber", job_url, jba.build);
+            let text = http::fetch_with(&policy, authorize(client.get(&url), source)).await?;
+            let text = String::from_utf8_lossy(&text);
+            let build_number = text.trim().parse()?;
+            log::info!("request: {}: {} is build {}", uri, jba.build, build_number);
+            jba.build_number = Some(build_number);
+            build_number
+        }
+    };
+
+    let builds_path = jba.builds_path(local_cache);
+    let path = builds_path.join(format!("{}", build_number));
+    if path.exists() {
+        log::info!("request: {}: artifacts {} exist", uri, path.display());
+        return Ok(());
+    }
+
+    // The artifacts of a running build, e.g. `lastBuild`, may not all be
+    // archived yet.
+    let url = format!("{}/{}/api/json?tree=building", job_url, build_number);
+    let body = http::fetch_with(&policy, authorize(client.get(&url), source)).await?;
+    let status: BuildStatus = serde_json::from_slice(&body)?;
+    if status.building {
+        return Err(Error::NotReady(format!(
+            "artifacts of job '{}' build {}, still running",
+            jba.job_path, build_number
+        )));
+    }
+
+    std::fs::create_dir_all(&builds_path)?;
+
+    let lockfile = std::fs::File::create(builds_path.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    let path_tmp = builds_path.join(format!("{}.tmp", build_number));
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    log::info!(
+        "request: {}: downloading artifacts of job '{}' build {}",
+        uri,
+        jba.job_path,
+        build_number
+    );
+
+    let url = format!("{}/{}/artifact/*zip*/archive.zip", job_url, build_number);
+    let archive_zip = path_tmp.join("archive.zip");
+    http::download_with(&policy, authorize(client.get(&url), source), &archive_zip).await?;
+
+    log::info!("request: {}: extracting artifacts", uri);
+    {
+        let archive_zip = archive_zip.display();
+        let path_tmp = path_tmp.display();
+        util::bash(format!("unzip {archive_zip} -d {path_tmp}"))?;
+    }
+
+    log::info!("request: {}: placing artifacts", uri);
+
+    // The zip holds everything under a top-level `archive/` directory.
+    util::commit_entry(&path_tmp.join("archive"), &path)?;
+    std::fs::remove_dir_all(path_tmp)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds() {
+        let jba = JenkinsBuildArtifact::new("ci", "products/foo/main".to_owned(), "42").unwrap();
+        assert_eq!(jba.build_number, Some(42));
+        assert_eq!(
+            jba.build_path(Path::new("/cache")),
+            Some(PathBuf::from("/cache/ci/products/foo/main/@builds/42"))
+        );
+
+        for permalink in PERMALINKS {
+            let jba = JenkinsBuildArtifact::new("ci", "foo".to_owned(), permalink).unwrap();
+            assert_eq!(jba.build_number, None);
+            assert_eq!(jba.build_path(Path::new("/cache")), None);
+        }
+
+        assert!(JenkinsBuildArtifact::new("ci", "foo".to_owned(), "lastFailedBuild").is_err());
+        assert!(JenkinsBuildArtifact::new("ci", "foo//bar".to_owned(), "1").is_err());
+        assert!(JenkinsBuildArtifact::new("ci", "foo bar".to_owned(), "1").is_err());
+        assert!(JenkinsBuildArtifact::new("ci", format!("foo/{}", BUILDS_DIR), "1").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, path::Path};
+
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{error::Error, util};
+
+/// Checksums of all the files in a `files` composite, in `sha256sum` format.
+pub const SUMS_FILE: &str = "SHA256SUMS";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Listing {
+    path: String,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Entry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+fn read_sums(root: &Path) -> Result<HashMap<String, String>, Error> {
+    let content = match std::fs::read_to_string(root.join(SUMS_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(sum, path)| (path.to_owned(), sum.to_owned()))
+        .collect())
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Serve a listing of the directory `rel` under the composite `root`.
+///
+/// `base` is the request path of the directory, used for building links.
+pub fn serve(root: &Path, rel: &str, base: &str, json: bool) -> Result<Response<Body>, Error> {
+    let sums = read_sums(root)?;
+    let rel = rel.trim_matches('/');
+
+    let mut entries = vec![];
+    for dir_entry in std::fs::read_dir(root.join(rel))? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        let metadata = std::fs::metadata(dir_entry.path())?;
+        let rel_path = if rel.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel, name)
+        };
+
+        entries.push(if metadata.is_dir() {
+            Entry {
+                name,
+                kind: "directory",
+                size: 0,
+                sha256: None,
+            }
+        } else {
+            Entry {
+                name,
+                kind: "file",
+                size: metadata.len(),
+                sha256: sums.get(&rel_path).cloned(),
+            }
+        });
+    }
+    entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+
+    let listing = Listing {
+        path: format!("/{}", rel),
+        entries,
+    };
+
+    let (content_type, body) = if json {
+        ("application/json", serde_json::to_string_pretty(&listing)?)
+    } else {
+        ("text/html; charset=utf-8", to_html(&listing, base))
+    };
+
+    let mut rsp = Response::new(Body::from(body));
+    *rsp.status_mut() = StatusCode::OK;
+    rsp.headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static(content_type));
+    Ok(rsp)
+}
+
+fn to_html(listing: &Listing, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let title = html_escape(&listing.path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {title}</title></head>\n<body>\n\
+         <h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>SHA256</th></tr>\n"
+    );
+
+    if listing.path != "/" {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}/..\">../</a></td><td></td><td></td></tr>\n",
+            html_escape(base)
+        ));
+    }
+
+    for entry in listing.entries.iter() {
+        // `base` comes from the request, so it's already percent-encoded.
+        let href = html_escape(&format!("{}/{}", base, util::percent_encode(&entry.name)));
+        let name = html_escape(&entry.name);
+        if entry.kind == "directory" {
+            html.push_str(&format!(
+                "<tr><td><a href=\"{href}/\">{name}/</a></td><td>-</td><td></td></tr>\n"
+            ));
+        } else {
+            html.push_str(&format!(
+                "<tr><td><a href=\"{href}\">{name}</a></td><td>{}</td><td><code>{}</code></td></tr>\n",
+                entry.size,
+                entry.sha256.as_deref().unwrap_or("")
+            ));
+        }
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_links() {
+        let listing = Listing {
+            path: "/a <b>".to_owned(),
+            entries: vec![
+                Entry {
+                    name: "x&y \"z\"".to_owned(),
+                    kind: "directory",
+                    size: 0,
+                    sha256: None,
+                },
+                Entry {
+                    name: "<script>#?%.rpm".to_owned(),
+                    kind: "file",
+                    size: 3,
+                    sha256: None,
+                },
+            ],
+        };
+
+        let html = to_html(&listing, "/loc/k/-/files/a%20%3Cb%3E/");
+        assert!(html.contains("<title>Index of /a &lt;b&gt;</title>"));
+        assert!(html.contains(
+            "<a href=\"/loc/k/-/files/a%20%3Cb%3E/x%26y%20%22z%22/\">x&amp;y &quot;z&quot;/</a>"
+        ));
+        assert!(html.contains(
+            "<a href=\"/loc/k/-/files/a%20%3Cb%3E/%3Cscript%3E%23%3F%25.rpm\">\
+             &lt;script&gt;#?%.rpm</a>"
+        ));
+        assert!(!html.contains("<script>"));
+    }
+}
@@ -23,13 +23,20 @@ pub struct Opt {
         default_value = "128"
     )]
     pub max_log_size: u64,
+
+    #[structopt(help = "Log line format (text/json)", long = "log-format", default_value = "text")]
+    pub log_format: String,
 }
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Invalid logging level")]
     InvalidLoggingLevel,
 
+    #[error("Invalid log format")]
+    InvalidLogFormat,
+
     #[error("Io error; {0}")]
     IoError(#[from] std::io::Error),
 
@@ -40,6 +47,82 @@ pub enum Error {
     FlexiLogger(#[from] flexi_logger::FlexiLoggerError),
 }
 
+tokio::task_local! {
+    /// The ID of the request being served, attached to its log lines.
+    pub static REQUEST_ID: String;
+}
+
+fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|x| x.clone()).ok()
+}
+
+/// Spawn a task that logs under the ID of the request spawning it, if any,
+/// as task locals aren't inherited by spawned tasks.
+pub fn spawn<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match request_id() {
+        Some(request_id) => tokio::spawn(REQUEST_ID.scope(request_id, fut)),
+        None => tokio::spawn(fut),
+    }
+}
+
+/// Take the ID of a request from its `X-Request-Id` header if it has a sane
+/// one, e.g. from a proxy in front, or generate one.
+pub fn new_request_id(header: Option<&str>) -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(header) = header {
+        let sane = header.len() <= 64
+            && header.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+        if sane && !header.is_empty() {
+            return header.to_owned();
+        }
+    }
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}{:08x}", (nanos >> 16) as u32, count as u32)
+}
+
+fn json_format(
+    w: &mut dyn std::io::Write,
+    _now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    let mut line = serde_json::json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "file": record.file(),
+        "line": record.line(),
+        "message": record.args().to_string(),
+    });
+    if let Some(request_id) = request_id() {
+        line["request_id"] = serde_json::Value::String(request_id);
+    }
+
+    write!(w, "{}", line)
+}
+
+fn detailed_format_with_request_id(
+    w: &mut dyn std::io::Write,
+    now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    if let Some(request_id) = request_id() {
+        write!(w, "[{}] ", request_id)?;
+    }
+    flexi_logger::detailed_format(w, now, record)
+}
+
 pub type FilterFunction = fn(&mut String, record: &log::Record) -> bool;
 
 static mut FILTER_FUNC: FilterFunction = empty_filter;
@@ -65,10 +148,14 @@ fn my_minimal_console_formatting(
     let now = chrono::Local::now();
     let mut args = record.args().to_string();
 
-    if !unsafe { FILTER_FUNC }(&mut args, &record) {
+    if !unsafe { FILTER_FUNC }(&mut args, record) {
         return Ok(());
     }
 
+    if let Some(request_id) = request_id() {
+        args = format!("[{}] {}", request_id, args);
+    }
+
     write!(
         w,
         "{} {} {}:{} {}",
@@ -106,10 +193,17 @@ pub fn activate(opt: &Opt, console_filter_func: FilterFunction) -> Result<(), Er
 
     logger = logger.set_palette("b1;3;2;4;6".to_owned());
 
+    let (file_format, stderr_format): (FormatFunction, FormatFunction) =
+        match opt.log_format.as_str() {
+            "text" => (detailed_format_with_request_id, my_minimal_console_formatting),
+            "json" => (json_format, json_format),
+            _ => return Err(Error::InvalidLogFormat),
+        };
+
     if let Some(log_file) = &opt.log_file {
         logger = logger
             .write_mode(WriteMode::Async)
-            .format_for_files(flexi_logger::detailed_format)
+            .format_for_files(file_format)
             .log_to_file(FileSpec::try_from(log_file)?);
         if !opt.stderr_logging_disable {
             logger = logger.print_message();
@@ -131,13 +225,13 @@ pub fn activate(opt: &Opt, console_filter_func: FilterFunction) -> Result<(), Er
                 Cleanup::KeepLogFiles(nr_files as usize),
             )
             .print_message()
-            .format_for_files(flexi_logger::detailed_format);
+            .format_for_files(file_format);
     };
 
     if !opt.stderr_logging_disable {
         logger = logger
             .adaptive_format_for_stderr(AdaptiveFormat::Detailed)
-            .format_for_stderr(my_minimal_console_formatting)
+            .format_for_stderr(stderr_format)
             .duplicate_to_stderr(if let Some(log_level) = &opt.log_level {
                 match log_level.as_str() {
                     "trace" => Duplicate::Trace,
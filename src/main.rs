@@ -1,7 +1,8 @@
 use std::path::{PathBuf, Path};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeSet, HashMap, VecDeque},
     convert::Infallible,
     net::ToSocketAddrs,
     str::FromStr,
@@ -10,7 +11,7 @@ use std::{
 use cmdline::CommandArgs;
 use error::Error;
 use fs2::FileExt;
-use gitlab::{api::AsyncQuery, AsyncGitlab, GitlabBuilder};
+use gitlab::{api::AsyncQuery, AsyncGitlab, GitlabBuilder, RestError};
 use hyper::StatusCode;
 use hyper::{
     http::uri::PathAndQuery,
@@ -19,37 +20,76 @@ use hyper::{
 };
 use regex::Regex;
 use structopt::StructOpt;
+use tokio::sync::watch;
 
+mod archive;
 mod artifacts;
+mod check;
 mod cmdline;
 mod config;
+mod crawl;
 mod error;
+mod explain;
+mod gc;
+mod gitea;
+mod http;
+mod jenkins;
+mod listing;
 mod logging;
+mod manifest;
+mod oci;
+mod peer;
+mod rpm;
+mod s3;
+mod status;
 mod util;
 
-use crate::config::{Config, GitlabJobSource, LocalPathSource, RemoteSource};
+use crate::config::{Config, GitlabJobSource, LocalPathSource, RemoteMode, RemoteSource};
+
+/// Header carrying the ID of a request, both ways.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How often the config file is checked for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 struct Main {
     config: Config,
+    opt: CommandArgs,
 }
 
 #[derive(Debug, Clone)]
 struct Plan {
     artifacts: Vec<Artifact>,
+    /// The file filter of each artifact, by the same index.
+    filters: Vec<Option<String>>,
+    /// The source spec of each artifact, by the same index.
+    specs: Vec<String>,
     sub_uri: String,
     kind: Kind,
+    archive: Option<archive::Format>,
+    rpm: rpm::Options,
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum Kind {
     RPM,
+    Pacman(String),
+    Files,
 }
 
 #[derive(Debug, Clone)]
 enum Artifact {
     GitlabJob(JobArtifact),
+    GitlabPackage(PackageArtifact),
+    GitlabRelease(ReleaseArtifact),
     Local(LocalArtifact),
     Remote(StaticRemoteArtifact),
+    Oci(oci::OciArtifact),
+    S3(s3::S3Artifact),
+    GiteaRun(gitea::GiteaRunArtifact),
+    JenkinsBuild(jenkins::JenkinsBuildArtifact),
+    Peer(peer::PeerArtifact),
 }
 
 #[derive(Debug, Clone)]
@@ -59,6 +99,69 @@ struct JobArtifact {
     job_id: u64,
 }
 
+impl JobArtifact {
+    /// The job as returned by the API, saved next to its artifacts.
+    fn job_json_path(&self, local_cache: &Path) -> PathBuf {
+        local_cache
+            .join(&self.source_name)
+            .join(&self.project)
+            .join(format!("{}.json", self.job_id))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PackageArtifact {
+    source_name: String,
+    project: String,
+    name: String,
+    version: String,
+}
+
+/// Spec component separating a GitLab project from a package name and version.
+const PACKAGE_MARKER: &str = "-package";
+
+impl PackageArtifact {
+    /// Packages are cached apart from the job artifacts of the projects.
+    fn package_path(&self, local_cache: &Path) -> PathBuf {
+        local_cache
+            .join(&self.source_name)
+            .join(PACKAGE_MARKER)
+            .join(&self.project)
+            .join(&self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ReleaseArtifact {
+    source_name: String,
+    project: String,
+    tag: String,
+}
+
+/// Spec component separating a GitLab project from a release tag.
+const RELEASE_MARKER: &str = "-release";
+
+impl ReleaseArtifact {
+    /// Releases are cached apart from the job artifacts of the projects.
+    fn release_path(&self, local_cache: &Path) -> PathBuf {
+        local_cache.join(&self.source_name).join(RELEASE_MARKER).join(&self.project)
+    }
+}
+
+/// The relative path under which to save a release asset: its direct asset
+/// path if it has one, otherwise its name.
+fn release_asset_path(name: &str, direct_asset_path: Option<&str>) -> Option<String> {
+    let path = direct_asset_path
+        .map(util::sanitize_rel_path)
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| util::sanitize_rel_path(name).replace('/', "_"));
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct LocalArtifact {
     source_name: String,
@@ -71,24 +174,130 @@ struct StaticRemoteArtifact {
     subpath: String,
 }
 
+impl Artifact {
+    /// The local directory holding the files of the artifact, once it is cached.
+    fn local_path(&self, config: &Config) -> Option<PathBuf> {
+        match self {
+            Artifact::GitlabJob(job) => {
+                if config.gitlabs.contains_key(&job.source_name) {
+                    let project_path =
+                        config.local_cache.join(&job.source_name).join(&job.project);
+                    Some(project_path.join(format!("{}", job.job_id)))
+                } else {
+                    None
+                }
+            }
+            Artifact::GitlabPackage(package) => {
+                if config.gitlabs.contains_key(&package.source_name) {
+                    Some(package.package_path(&config.local_cache).join(&package.version))
+                } else {
+                    None
+                }
+            }
+            Artifact::GitlabRelease(release) => {
+                if config.gitlabs.contains_key(&release.source_name) {
+                    Some(release.release_path(&config.local_cache).join(&release.tag))
+                } else {
+                    None
+                }
+            }
+            Artifact::Local(local) => {
+                config.local_source.get(&local.source_name).map(|x| x.root.join(&local.key))
+            }
+            Artifact::Remote(remote) => {
+                if config.remote_source.contains_key(&remote.source_name) {
+                    let cache_path = config.local_cache.join(&remote.source_name);
+                    Some(cache_path.join(&remote.subpath))
+                } else {
+                    None
+                }
+            }
+            Artifact::Oci(oci) => {
+                if let (Some(_), Some(digest)) =
+                    (config.oci_source.get(&oci.source_name), &oci.digest)
+                {
+                    let repo_path = config.local_cache.join(&oci.source_name).join(&oci.repo);
+                    Some(repo_path.join(digest))
+                } else {
+                    None
+                }
+            }
+            Artifact::S3(s3a) => {
+                if let (Some(_), Some(fingerprint)) =
+                    (config.s3_source.get(&s3a.source_name), &s3a.fingerprint)
+                {
+                    let prefix_path = config.local_cache.join(&s3a.source_name).join(&s3a.prefix);
+                    Some(prefix_path.join(fingerprint))
+                } else {
+                    None
+                }
+            }
+            Artifact::GiteaRun(run) => {
+                if config.gitea_source.contains_key(&run.source_name) {
+                    let repo_path = run.repo_path(&config.local_cache);
+                    Some(repo_path.join(format!("{}", run.run_id)))
+                } else {
+                    None
+                }
+            }
+            Artifact::JenkinsBuild(jba) => {
+                if config.jenkins_source.contains_key(&jba.source_name) {
+                    jba.build_path(&config.local_cache)
+                } else {
+                    None
+                }
+            }
+            Artifact::Peer(pa) => {
+                if config.speardrive_source.contains_key(&pa.source_name) {
+                    pa.cache_path(&config.local_cache)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Kind {
+    fn name(&self) -> &str {
+        match self {
+            Kind::RPM => "rpm",
+            Kind::Pacman(name) => name,
+            Kind::Files => "files",
+        }
+    }
+}
+
 impl Plan {
     fn to_composite_path(&self) -> String {
         use sha2::{Digest, Sha256};
 
         let mut hasher = Sha256::new();
         let without_suburi = Self {
+            specs: vec![],
             sub_uri: "".to_owned(),
+            archive: None,
             ..(*self).clone()
         };
         let rep = format!("{:?}", without_suburi);
         hasher.update(rep.as_bytes());
         let result = hasher.finalize();
 
-        return format!("{}", hex::encode(result));
+        hex::encode(result)
+    }
+
+    /// Split the `:<filter>` suffix off a source spec.
+    fn split_filter(item: &str) -> (&str, Option<&str>) {
+        match item.split_once(':') {
+            Some((spec, filter)) => (spec, Some(filter)),
+            None => (item, None),
+        }
     }
 
-    fn from_uri(uri: &str, config: &Arc<Config>) -> Result<Plan, Error> {
+    fn from_uri(uri: &str, query: Option<&str>, config: &Arc<Config>) -> Result<Plan, Error> {
         let mut artifacts = vec![];
+        let mut filters = vec![];
+        let mut specs = vec![];
 
         let comps = uri.split("/").collect::<Vec<&str>>();
         if comps.len() <= 2 {
@@ -96,11 +305,14 @@ impl Plan {
         }
 
         let mut sub_uri = String::new();
-        let kind = Kind::RPM;
+        let mut kind = Kind::RPM;
+        let mut archive = None;
 
         for item in comps[1..].join("/").split("/-/") {
             lazy_static::lazy_static! {
                 static ref RE: Regex = Regex::new("[/a-z0-9_-]+").unwrap();
+                static ref PACKAGE_RE: Regex = Regex::new("^[A-Za-z0-9._+~-]+$").unwrap();
+                static ref PACMAN_REPO_RE: Regex = Regex::new("^[a-zA-Z0-9_+-][a-zA-Z0-9_.+-]*$").unwrap();
             }
 
             let mut parts: VecDeque<_> = item.split("/").collect();
@@ -111,15 +323,111 @@ impl Plan {
                 continue;
             };
 
+            if status::RESERVED_NAMES.contains(&prefix) {
+                return Err(Error::PlanParse(format!("{} is a reserved path", prefix)));
+            }
+
+            // `<kind>.<archive-suffix>` requests the entire composite as an archive.
+            let prefix = match archive::Format::split_suffix(prefix) {
+                Some((base, format)) if parts.is_empty() && (base == "rpm" || base == "files") => {
+                    archive = Some(format);
+                    base
+                }
+                _ => prefix,
+            };
+
             if prefix == "rpm" {
                 sub_uri = format!("/{}", parts.into_iter().collect::<Vec<_>>().join("/"));
                 continue;
             }
 
+            if prefix == "files" {
+                kind = Kind::Files;
+                sub_uri = format!("/{}", parts.into_iter().collect::<Vec<_>>().join("/"));
+                continue;
+            }
+
+            if prefix == "pacman" {
+                let mut name = parts.pop_front().unwrap_or("");
+                if parts.is_empty() {
+                    if let Some((base, format)) = archive::Format::split_suffix(name) {
+                        archive = Some(format);
+                        name = base;
+                    }
+                }
+                if !PACMAN_REPO_RE.is_match(name) {
+                    return Err(Error::PlanParse(format!("{} invalid pacman repo name", name)));
+                }
+
+                kind = Kind::Pacman(name.to_owned());
+                sub_uri = format!("/{}", parts.into_iter().collect::<Vec<_>>().join("/"));
+                continue;
+            }
+
+            // Source specs may end with a `:<filter>` of the files to use.
+            // OCI specs, and the peer specs that may wrap them, can end with
+            // a digest instead, which holds a colon.
+            let digest_split = if config.oci_source.contains_key(prefix)
+                || config.speardrive_source.contains_key(prefix)
+            {
+                oci::split_filter(item)
+            } else {
+                None
+            };
+            let (spec, filter) = digest_split.unwrap_or_else(|| Self::split_filter(item));
+
             // For sanity, remove parts that can be '..'.
-            let mut parts: VecDeque<_> = parts.into_iter().filter(|x| *x != "..").collect();
+            let mut parts: VecDeque<_> =
+                spec.split("/").skip(1).filter(|x| *x != "..").collect();
+
+            // GitLab doesn't allow paths starting with `-`, so these markers
+            // can't be mistaken for a project.
+            if config.gitlabs.contains_key(prefix)
+                && parts.len() >= 4
+                && parts[parts.len() - 3] == PACKAGE_MARKER
+            {
+                let version = parts.pop_back().unwrap();
+                let name = parts.pop_back().unwrap();
+                parts.pop_back();
+                let project = parts.into_iter().collect::<Vec<_>>().join("/");
+
+                if !RE.is_match(&project) {
+                    return Err(Error::PlanParse(format!("{} invalid project name", project)));
+                }
+                if !PACKAGE_RE.is_match(name) || !PACKAGE_RE.is_match(version) {
+                    return Err(Error::PlanParse(format!(
+                        "{}/{} invalid package name or version",
+                        name, version
+                    )));
+                }
 
-            if let Some(_) = config.gitlabs.get(prefix) {
+                artifacts.push(Artifact::GitlabPackage(PackageArtifact {
+                    source_name: prefix.to_owned(),
+                    project,
+                    name: name.to_owned(),
+                    version: version.to_owned(),
+                }))
+            } else if config.gitlabs.contains_key(prefix)
+                && parts.len() >= 3
+                && parts[parts.len() - 2] == RELEASE_MARKER
+            {
+                let tag = parts.pop_back().unwrap();
+                parts.pop_back();
+                let project = parts.into_iter().collect::<Vec<_>>().join("/");
+
+                if !RE.is_match(&project) {
+                    return Err(Error::PlanParse(format!("{} invalid project name", project)));
+                }
+                if !PACKAGE_RE.is_match(tag) {
+                    return Err(Error::PlanParse(format!("{} invalid release tag", tag)));
+                }
+
+                artifacts.push(Artifact::GitlabRelease(ReleaseArtifact {
+                    source_name: prefix.to_owned(),
+                    project,
+                    tag: tag.to_owned(),
+                }))
+            } else if config.gitlabs.contains_key(prefix) {
                 if let Some(job_id) = parts.pop_back() {
                     let project = parts.into_iter().collect::<Vec<_>>().join("/");
 
@@ -136,7 +444,7 @@ impl Plan {
                         job_id: job_id.parse()?,
                     }))
                 }
-            } else if let Some(_) = config.local_source.get(prefix) {
+            } else if config.local_source.contains_key(prefix) {
                 if let Some(key) = parts.pop_back() {
                     if key != ".." {
                         artifacts.push(Artifact::Local(LocalArtifact {
@@ -145,20 +453,78 @@ impl Plan {
                         }))
                     }
                 }
-            } else if let Some(_) = config.remote_source.get(prefix) {
+            } else if config.remote_source.contains_key(prefix) {
                 artifacts.push(Artifact::Remote(StaticRemoteArtifact {
                     subpath: parts.into_iter().collect::<Vec<_>>().join("/"),
                     source_name: prefix.to_owned(),
                 }))
+            } else if config.oci_source.contains_key(prefix) {
+                if let Some(reference) = parts.pop_back() {
+                    let repo = parts.into_iter().collect::<Vec<_>>().join("/");
+                    artifacts.push(Artifact::Oci(oci::OciArtifact::new(prefix, repo, reference)?))
+                }
+            } else if config.s3_source.contains_key(prefix) {
+                artifacts.push(Artifact::S3(s3::S3Artifact {
+                    source_name: prefix.to_owned(),
+                    prefix: parts.into_iter().filter(|x| !x.is_empty()).collect::<Vec<_>>().join("/"),
+                    fingerprint: None,
+                }))
+            } else if config.gitea_source.contains_key(prefix) {
+                if let [owner, repo, run_id] = parts.make_contiguous() {
+                    artifacts.push(Artifact::GiteaRun(gitea::GiteaRunArtifact::new(
+                        prefix, owner, repo, run_id,
+                    )?))
+                } else {
+                    return Err(Error::PlanParse(format!(
+                        "{} expects <owner>/<repo>/<run>",
+                        prefix
+                    )));
+                }
+            } else if config.jenkins_source.contains_key(prefix) {
+                if let Some(build) = parts.pop_back() {
+                    let job_path = parts.into_iter().collect::<Vec<_>>().join("/");
+                    artifacts.push(Artifact::JenkinsBuild(jenkins::JenkinsBuildArtifact::new(
+                        prefix, job_path, build,
+                    )?))
+                }
+            } else if config.speardrive_source.contains_key(prefix) {
+                let spec = parts.into_iter().filter(|x| !x.is_empty()).collect::<Vec<_>>();
+                if spec.is_empty() {
+                    return Err(Error::PlanParse(format!("{} expects a source spec", prefix)));
+                }
+
+                artifacts.push(Artifact::Peer(peer::PeerArtifact {
+                    source_name: prefix.to_owned(),
+                    spec: spec.join("/"),
+                    identity: None,
+                }))
             } else {
                 return Err(Error::UnknownSource(prefix.into()));
             }
+
+            if artifacts.len() > filters.len() {
+                let filter = filter.map(|x| x.to_owned()).or_else(|| config.source_filter(prefix).cloned());
+                filters.push(filter.filter(|x| !x.is_empty()));
+                specs.push(spec.to_owned());
+            }
+        }
+
+        let rpm = rpm::Options::from_query(query)?;
+        if !rpm.is_default() && !matches!(kind, Kind::RPM) {
+            return Err(Error::PlanParse(format!(
+                "RPM options given for a {} repo",
+                kind.name()
+            )));
         }
 
         Ok(Plan {
             artifacts,
+            filters,
+            specs,
             sub_uri,
             kind,
+            archive,
+            rpm,
         })
     }
 }
@@ -180,8 +546,14 @@ impl ClientCache {
         gpipe: &GitlabJobSource,
     ) -> Result<&mut AsyncGitlab, Error> {
         if !self.gitlab_clients.contains_key(name) {
-            let builder = GitlabBuilder::new(&gpipe.hostname, &gpipe.api_key);
-            let gitlab = builder.build_async().await?;
+            // Building the client checks the credentials with a query, which
+            // the client itself has no timeout for.
+            let policy = http::policy(gpipe.http.as_ref());
+            let builder = GitlabBuilder::new(&gpipe.hostname, gpipe.api_key.expose());
+            let gitlab = http::retry(&policy, &gpipe.hostname, || {
+                http::timeout(&policy, &gpipe.hostname, async { Ok(builder.build_async().await?) })
+            })
+            .await?;
 
             self.gitlab_clients.insert(name.clone(), gitlab);
         }
@@ -190,48 +562,118 @@ impl ClientCache {
     }
 }
 
-async fn service_handle(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>, Error> {
-    let uri = req.uri().to_string();
-    log::info!("request: {}", uri);
+/// Keep the transport errors and server statuses of a GitLab query, so that
+/// the transient ones are retried.
+fn gitlab_error(what: &str, err: gitlab::api::ApiError<RestError>) -> Error {
+    match err {
+        gitlab::api::ApiError::Client {
+            source: RestError::Communication { source },
+        } => Error::Reqwest(source),
+        gitlab::api::ApiError::GitlabService { status, .. } => {
+            Error::HttpStatus(what.to_owned(), status)
+        }
+        err => Error::Boxed(Arc::new(err)),
+    }
+}
 
-    let plan = Plan::from_uri(&uri, &config)?;
-    log::info!("request: plan - {:?}", plan);
+/// Run a GitLab query, retrying its transient failures. The GitLab client has
+/// no timeouts, so the whole query, connecting included, is bounded by the
+/// read timeout.
+async fn gitlab_query<T, Q>(
+    policy: &http::Policy,
+    what: &str,
+    query: &Q,
+    client: &AsyncGitlab,
+) -> Result<T, Error>
+where
+    Q: AsyncQuery<T, AsyncGitlab> + Sync,
+{
+    http::retry(policy, what, || async move {
+        http::timeout(policy, what, async {
+            query.query_async(client).await.map_err(|x| gitlab_error(what, x))
+        })
+        .await
+    })
+    .await
+}
+
+/// Download the file of a GitLab API endpoint with the shared HTTP client,
+/// for the read timeout to apply to each read rather than to the whole file.
+async fn gitlab_download(
+    policy: &http::Policy,
+    gpipe: &GitlabJobSource,
+    endpoint: &impl gitlab::api::Endpoint,
+    path: &Path,
+) -> Result<(), Error> {
+    let url = format!("https://{}/api/v4/{}", gpipe.hostname, endpoint.endpoint());
+    let req = http::client_for(policy)
+        .get(url)
+        .header("PRIVATE-TOKEN", gpipe.api_key.expose());
+    http::download_with(policy, req, path).await
+}
 
+/// Make sure all the artifacts of the plan are in the local cache, resolving
+/// the ones given by mutable references on the way.
+async fn cache_artifacts(config: &Config, plan: &mut Plan, uri: &String) -> Result<(), Error> {
     let mut gitlab = ClientCache::new();
 
-    for artifact in plan.artifacts.iter() {
+    for artifact in plan.artifacts.iter_mut() {
         match artifact {
             Artifact::GitlabJob(job) => {
                 if let Some(gpipe) = config.gitlabs.get(&job.source_name) {
                     let project_path = config.local_cache.join(&job.source_name).join(&job.project);
-                    let lock = project_path.join(format!("lock"));
+                    let lock = project_path.join("lock");
                     let path_tmp = project_path.join(format!("{}.tmp", job.job_id));
                     let path = project_path.join(format!("{}", job.job_id));
 
                     if path.exists() {
                         log::info!("request: {}: artifacts {} exist", uri, path.display());
-                        continue;
+                    } else {
+                        cache_gitlab_job_artifacts(
+                            project_path,
+                            lock,
+                            path_tmp,
+                            job,
+                            gpipe,
+                            uri,
+                            path,
+                        )
+                        .await?;
+
+                        // The manifests do without the job's metadata rather
+                        // than fail the request. It's only fetched along with
+                        // the artifacts, keeping Gitlab off cached requests.
+                        let saved = cache_gitlab_job_json(
+                            &config.local_cache,
+                            job,
+                            gpipe,
+                            uri,
+                            &mut gitlab,
+                        );
+                        if let Err(err) = saved.await {
+                            log::warn!("request: {}: saving job {}: {}", uri, job.job_id, err);
+                        }
                     }
-
-                    cache_gitlab_job_artifacts(
-                        project_path,
-                        lock,
-                        path_tmp,
-                        &job,
-                        gpipe,
-                        &uri,
-                        &mut gitlab,
-                        path,
-                    )
-                    .await?;
+                }
+            }
+            Artifact::GitlabPackage(package) => {
+                if let Some(gpipe) = config.gitlabs.get(&package.source_name) {
+                    cache_gitlab_package(&config.local_cache, package, gpipe, uri, &mut gitlab)
+                        .await?;
+                }
+            }
+            Artifact::GitlabRelease(release) => {
+                if let Some(gpipe) = config.gitlabs.get(&release.source_name) {
+                    cache_gitlab_release(&config.local_cache, release, gpipe, uri, &mut gitlab)
+                        .await?;
                 }
             }
             Artifact::Remote(sra) => {
                 if let Some(sr) = config.remote_source.get(&sra.source_name) {
                     let orig_path = config.local_cache.join(&sra.source_name);
-                    let lock = orig_path.join(format!("lock"));
+                    let lock = orig_path.join("lock");
                     let path_tmp = orig_path.join(format!("{}.tmp", sra.subpath));
-                    let path = orig_path.join(format!("{}", sra.subpath));
+                    let path = orig_path.join(&sra.subpath);
 
                     if path.exists() {
                         log::info!("request: {}: static remote copy {} exist", uri, path.display());
@@ -242,167 +684,773 @@ async fn service_handle(config: Arc<Config>, req: Request<Body>) -> Result<Respo
                         orig_path,
                         lock,
                         path_tmp,
-                        &sra,
+                        sra,
                         sr,
-                        &uri,
+                        uri,
                         path,
                     )
                     .await?;
                 }
             },
+            Artifact::Oci(oa) => {
+                if let Some(os) = config.oci_source.get(&oa.source_name) {
+                    oci::cache_artifact(&config.local_cache, oa, os, uri).await?;
+                }
+            }
+            Artifact::S3(s3a) => {
+                if let Some(s3) = config.s3_source.get(&s3a.source_name) {
+                    s3::cache_artifact(&config.local_cache, s3a, s3, uri).await?;
+                }
+            }
+            Artifact::GiteaRun(run) => {
+                if let Some(gitea) = config.gitea_source.get(&run.source_name) {
+                    gitea::cache_artifact(&config.local_cache, run, gitea, uri).await?;
+                }
+            }
+            Artifact::JenkinsBuild(jba) => {
+                if let Some(jenkins) = config.jenkins_source.get(&jba.source_name) {
+                    jenkins::cache_artifact(&config.local_cache, jba, jenkins, uri).await?;
+                }
+            }
+            Artifact::Peer(pa) => {
+                if let Some(peer) = config.speardrive_source.get(&pa.source_name) {
+                    peer::cache_artifact(&config.local_cache, pa, peer, uri).await?;
+                }
+            }
             Artifact::Local(_) => {}
         }
     }
 
-    // Create composite directory
-    let lock = config.composites_cache.join(format!("lock"));
-    let node_name = plan.to_composite_path();
-    let composite_path = config.composites_cache.join(&node_name);
-    let path_tmp = config.composites_cache.join(format!("{}.tmp", node_name));
-
-    if !composite_path.exists() {
-        log::info!(
-            "request: {}: creating composite path {}",
-            uri,
-            composite_path.display()
-        );
+    Ok(())
+}
 
-        let _ = std::fs::create_dir_all(&config.composites_cache)?;
+/// The path and identity of an artifact being cached for export, once done,
+/// or the error caching it failed with.
+type Prepared = Option<Result<(PathBuf, String), Arc<Error>>>;
 
-        let lockfile = std::fs::File::create(&lock)?;
-        lockfile.lock_exclusive()?;
+lazy_static::lazy_static! {
+    /// The exports being cached in the background, by source spec.
+    static ref PREPARING: std::sync::Mutex<HashMap<String, watch::Receiver<Prepared>>> =
+        Default::default();
+}
 
-        let _ = std::fs::remove_dir_all(&path_tmp);
-        std::fs::create_dir_all(&path_tmp)?;
+/// Cache the artifact of a single source spec for export, in a task of its
+/// own so that it completes even if the peer asking for it stops waiting.
+/// Requests for a spec already being cached share its task.
+fn prepare_export(config: &Arc<Config>, plan: Plan, uri: &str) -> watch::Receiver<Prepared> {
+    let spec = plan.specs[0].clone();
+    let mut preparing = PREPARING.lock().unwrap();
+    if let Some(rx) = preparing.get(&spec) {
+        return rx.clone();
+    }
 
-        for (idx, artifact) in plan.artifacts.iter().enumerate() {
-            let path_dest = path_tmp.join(format!("{idx}"));
-            let path_dest = path_dest.display();
+    let (tx, rx) = watch::channel(None);
+    preparing.insert(spec.clone(), rx.clone());
+
+    let (config, uri) = (config.clone(), uri.to_owned());
+    logging::spawn(async move {
+        let mut plan = plan;
+        let result = async {
+            cache_artifacts(&config, &mut plan, &uri).await?;
+            let path = plan.artifacts[0]
+                .local_path(&config)
+                .ok_or_else(|| Error::UnknownSource(spec.clone()))?;
+            let identity = peer::identity(&config.local_cache, &path)?;
+            Ok((path, identity))
+        };
+        let result = result.await.map_err(Arc::new);
+        PREPARING.lock().unwrap().remove(&spec);
+        let _ = tx.send(Some(result));
+    });
 
-            let artifact_path = match artifact {
-                Artifact::GitlabJob(job) => {
-                    if let Some(_) = config.gitlabs.get(&job.source_name) {
-                        let project_path =
-                            config.local_cache.join(&job.source_name).join(&job.project);
-                        Some(project_path.join(format!("{}", job.job_id)))
-                    } else {
-                        None
-                    }
-                }
-                Artifact::Local(local) => {
-                    if let Some(local_source) = config.local_source.get(&local.source_name) {
-                        Some(local_source.root.join(&local.key))
-                    } else {
-                        None
-                    }
-                }
-                Artifact::Remote(remote) => {
-                    if let Some(_) = config.remote_source.get(&remote.source_name) {
-                        let cache_path = config.local_cache.join(&remote.source_name);
-                        Some(cache_path.join(&remote.subpath))
-                    } else {
-                        None
-                    }
-                }
-            };
+    rx
+}
 
-            if let Some(artifact_path) = artifact_path {
-                let artifact_path = artifact_path.display();
-                util::bash(format!(
-                        "cp -al {artifact_path} {path_dest}/ || cp -a {artifact_path} {path_dest}/"
-                ))?;
-            }
+/// Wait for an export being cached in the background.
+async fn prepared(rx: &mut watch::Receiver<Prepared>) -> Result<(PathBuf, String), Error> {
+    loop {
+        let done = rx.borrow().clone();
+        if let Some(result) = done {
+            return result.map_err(unshare);
         }
-
-        std::fs::write(path_tmp.join("url.txt"), uri)?;
-
-        match plan.kind {
-            Kind::RPM => {
-                let path_tmp = path_tmp.display();
-                util::bash(format!("createrepo {path_tmp}"))?;
-            }
+        if rx.changed().await.is_err() {
+            return Err(Error::NotReady("export task ended without a result".to_owned()));
         }
-
-        std::fs::rename(path_tmp, &composite_path)?;
     }
+}
 
-    let static_ = hyper_staticfile::Static::new(&composite_path);
+/// Take back an error shared by the requests waiting for the same task,
+/// keeping the kinds that are answered with their own status.
+fn unshare(err: Arc<Error>) -> Error {
+    match &*err {
+        Error::NotFound(x) => Error::NotFound(x.clone()),
+        Error::Conflict(x) => Error::Conflict(x.clone()),
+        Error::NotReady(x) => Error::NotReady(x.clone()),
+        _ => Error::Boxed(err),
+    }
+}
 
-    let mut req = req;
-    let mut parts = req.uri().clone().into_parts();
-    if let Some(p) = &mut parts.path_and_query {
-        *p = PathAndQuery::from_str(&plan.sub_uri).unwrap();
+/// Serve the cached files of a single source spec as a tarball, for peers
+/// using this instance as a source. A HEAD request only reports what is
+/// already cached, unless it asks to prepare the export, in which case it is
+/// answered with 202 while the artifact is still being cached.
+async fn export_artifact(
+    config: &Arc<Config>,
+    req: &Request<Body>,
+    spec: &str,
+    uri: &str,
+) -> Result<Response<Body>, Error> {
+    let plan = Plan::from_uri(&format!("/{}", spec), None, config)?;
+    if plan.artifacts.len() != 1 {
+        return Err(Error::PlanParse(format!("{} is not a single source spec", spec)));
     }
-    *req.uri_mut() = Uri::from_parts(parts)?;
 
-    log::info!("request: serving from {}/{}", composite_path.display(), req.uri());
+    let param = |name: &str| {
+        let mut params = req.uri().query().into_iter().flat_map(|x| x.split('&'));
+        params.find_map(|x| x.strip_prefix(name).and_then(|x| x.strip_prefix('=')))
+    };
+    let head = req.method() == hyper::Method::HEAD;
+
+    let (path, identity) = if head && param(peer::PREPARE_PARAM).is_none() {
+        let path = plan.artifacts[0]
+            .local_path(config)
+            .filter(|x| x.exists())
+            .ok_or_else(|| Error::NotFound(format!("{} is not cached", spec)))?;
+        let identity = peer::identity(&config.local_cache, &path)?;
+        (path, identity)
+    } else {
+        let mut rx = prepare_export(config, plan, uri);
+        if head {
+            match tokio::time::timeout(peer::PREPARE_WAIT, prepared(&mut rx)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    let mut rsp = Response::new(Body::empty());
+                    *rsp.status_mut() = StatusCode::ACCEPTED;
+                    return Ok(rsp);
+                }
+            }
+        } else {
+            prepared(&mut rx).await?
+        }
+    };
+
+    if let Some(expected) = param(peer::IDENTITY_PARAM) {
+        if expected != identity {
+            return Err(Error::PlanParse(format!("{} no longer resolves to {}", spec, expected)));
+        }
+    }
 
-    Ok(static_.serve(req).await?)
+    let mut rsp = if head {
+        Response::new(Body::empty())
+    } else {
+        let mut in_use = util::InUse::default();
+        if path.starts_with(&config.local_cache) && !in_use.add(&path)? {
+            return Err(Error::NotReady(format!("{} was removed meanwhile", spec)));
+        }
+        log::info!("request: exporting {}", path.display());
+        archive::serve(&path, archive::Format::Tar, "artifact", in_use)?
+    };
+    rsp.headers_mut().insert(
+        peer::IDENTITY_HEADER,
+        hyper::header::HeaderValue::from_str(&identity).unwrap(),
+    );
+    Ok(rsp)
 }
 
-async fn service_handle_wrapper(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>, Error> {
-    let uri = req.uri().to_string();
-    match service_handle(config, req).await {
-        Ok(v) => Ok(v),
-        Err(err) => {
-            log::error!("request: {}, failed: {}", uri, err);
-            let mut rsp = Response::new(Body::from(format!("{:?}", err)));
-            *rsp.status_mut() = StatusCode::BAD_REQUEST;
-            Ok(rsp)
-        },
+/// Whether a file is a pacman package, and not e.g. its signature.
+fn is_pacman_package(file_name: &str) -> bool {
+    match file_name.rsplit_once(".pkg.tar.") {
+        Some((base, ext)) => {
+            !base.is_empty() && !ext.is_empty() && ext.bytes().all(|x| x.is_ascii_alphanumeric())
+        }
+        None => false,
     }
 }
 
-async fn cache_gitlab_job_artifacts(
-    project_path: PathBuf,
-    lock: PathBuf,
-    path_tmp: PathBuf,
-    job: &JobArtifact,
-    gpipe: &GitlabJobSource,
-    uri: &String,
-    gitlab: &mut ClientCache,
-    path: PathBuf,
-) -> Result<(), Error> {
-    std::fs::create_dir_all(&project_path)?;
-
-    let lockfile = std::fs::File::create(&lock)?;
-    lockfile.lock_exclusive()?;
-
-    log::info!(
-        "request: {}: querying project '{}' job '{}'",
-        uri,
-        job.project,
-        job.job_id
-    );
+/// Place the artifacts of the plan in the temporary directory of its
+/// composite and generate the repository metadata there.
+fn build_composite(config: &Config, plan: &Plan, uri: &str, path_tmp: &Path) -> Result<(), Error> {
+    for (idx, artifact) in plan.artifacts.iter().enumerate() {
+        let path_dest = path_tmp.join(format!("{idx}"));
+
+        let artifact_path = artifact.local_path(config);
+
+        if let (Some(artifact_path), Some(filter)) = (&artifact_path, &plan.filters[idx]) {
+            // A filter without wildcards selects a subdirectory.
+            let filter = filter.trim_end_matches('/');
+            let filter = util::PathFilter::new(&[filter.to_owned(), format!("{}/**", filter)], &[])?;
+            let placed = util::place_filtered(artifact_path, &path_dest, &filter)?;
+            log::info!("request: {}: placed {} filtered files of {}", uri, placed, artifact_path.display());
+        } else if let Some(artifact_path) = artifact_path {
+            let artifact_path = artifact_path.display();
+            let path_dest = path_dest.display();
+            util::bash(format!(
+                "cp -al {artifact_path} {path_dest}/ || cp -a {artifact_path} {path_dest}/"
+            ))?;
+        }
+    }
 
-    let _ = std::fs::remove_dir_all(&path_tmp);
-    std::fs::create_dir_all(&path_tmp)?;
-    let endpoint = artifacts::JobArtifacts::builder()
-        .project(job.project.clone())
-        .job(job.job_id)
-        .build()
-        .map_err(Error::BuilderError)?;
+    std::fs::write(path_tmp.join("url.txt"), uri)?;
 
-    log::info!("request: {}: downloading artifacts", uri);
+    match &plan.kind {
+        Kind::RPM => {
+            if !plan.rpm.is_default() {
+                let removed = rpm::apply(path_tmp, &plan.rpm, uri)?;
+                log::info!("request: {}: filtered out {} packages", uri, removed);
+            }
 
-    let content = gitlab::api::raw(endpoint)
-        .query_async(gitlab.get(&job.source_name, gpipe).await?)
-        .await
-        .map_err(|x| Error::Boxed(Arc::new(x)))?;
-    let artifacts_zip = path_tmp.join("artifacts_zip");
-    std::fs::write(&artifacts_zip, content)?;
+            let path_tmp = path_tmp.display();
+            util::bash(format!("createrepo {path_tmp}"))?;
+        }
+        Kind::Pacman(name) => {
+            // repo-add given no packages waits for them on its input.
+            let packages = link_pacman_packages(path_tmp, &plan.specs)?;
+            if packages.is_empty() {
+                return Err(Error::NotFound(format!("pacman packages for {}", uri)));
+            }
 
-    log::info!("request: {}: extracting artifacts", uri);
-    {
-        let artifacts_zip = artifacts_zip.display();
-        let path_tmp = path_tmp.display();
-        util::bash(format!("unzip {artifacts_zip} -d {path_tmp}"))?;
+            let path_tmp = path_tmp.display();
+            util::bash(format!(
+                "cd {path_tmp} && \
+                 repo-add -q {name}.db.tar.gz $(ls | grep -E '\\.pkg\\.tar\\.[a-z0-9]+$')"
+            ))?;
+        }
+        Kind::Files => {
+            let path_tmp = path_tmp.display();
+            let sums = listing::SUMS_FILE;
+            util::bash(format!(
+                "cd {path_tmp} && \
+                 find . -type f ! -name {sums} -printf '%P\\0' | sort -z | xargs -0 -r sha256sum > {sums}"
+            ))?;
+        }
     }
 
-    log::info!("request: {}: placing artifacts", uri);
+    let mut sources = vec![];
+    for (idx, artifact) in plan.artifacts.iter().enumerate() {
+        let cache_path = artifact.local_path(config);
+        let cached = cache_path
+            .as_ref()
+            .and_then(|x| std::fs::metadata(x).ok())
+            .and_then(|x| x.modified().ok())
+            .map(|x| chrono::DateTime::<chrono::Utc>::from(x).to_rfc3339());
+        let gitlab_job = match artifact {
+            Artifact::GitlabJob(job) => {
+                manifest::GitlabJob::load(&job.job_json_path(&config.local_cache))
+            }
+            _ => None,
+        };
 
-    std::fs::remove_file(artifacts_zip)?;
-    std::fs::rename(path_tmp, path)?;
+        sources.push(manifest::Source {
+            spec: plan.specs[idx].clone(),
+            filter: plan.filters[idx].clone(),
+            cache_path,
+            cached,
+            gitlab_job,
+        });
+    }
+    manifest::Manifest::build(path_tmp, uri, sources)?.write(path_tmp)?;
+
+    Ok(())
+}
+
+/// Hardlink the packages of a pacman composite, with their signatures, to its
+/// top, where pacman fetches them relative to the database. Sources may ship
+/// the same file, but not different files of the same name. Returns the names
+/// of the packages.
+fn link_pacman_packages(path_tmp: &Path, specs: &[String]) -> Result<Vec<String>, Error> {
+    let mut linked: HashMap<String, PathBuf> = HashMap::new();
+    let mut packages = vec![];
+
+    for rel in util::walk_files(path_tmp)? {
+        let file_name = match rel.file_name() {
+            Some(x) if rel.components().count() > 1 => x.to_string_lossy().into_owned(),
+            _ => continue,
+        };
+        if !file_name.contains(".pkg.tar.") {
+            continue;
+        }
+
+        if let Some(other) = linked.get(&file_name) {
+            if !util::same_contents(&path_tmp.join(other), &path_tmp.join(&rel))? {
+                let spec = |rel: &Path| -> String {
+                    let idx = rel.components().next().and_then(|x| {
+                        x.as_os_str().to_str().and_then(|x| x.parse::<usize>().ok())
+                    });
+                    idx.and_then(|x| specs.get(x)).cloned().unwrap_or_default()
+                };
+                return Err(Error::Conflict(format!(
+                    "{} differs between {} and {}",
+                    file_name,
+                    spec(other),
+                    spec(&rel)
+                )));
+            }
+            continue;
+        }
+
+        std::fs::hard_link(path_tmp.join(&rel), path_tmp.join(&file_name))?;
+        if is_pacman_package(&file_name) {
+            packages.push(file_name.clone());
+        }
+        linked.insert(file_name, rel);
+    }
+
+    Ok(packages)
+}
+
+/// How many times the artifacts of a plan are cached again when gc removes
+/// them, or the composite, before the request gets to use them.
+const USE_ATTEMPTS: usize = 3;
+
+/// Mark the cached artifacts of the plan as in use. Returns `None` if any of
+/// them was removed since it was cached.
+fn use_artifacts(config: &Config, plan: &Plan) -> Result<Option<util::InUse>, Error> {
+    let mut in_use = util::InUse::default();
+    for artifact in plan.artifacts.iter() {
+        let path = artifact.local_path(config);
+        if let Some(path) = path.filter(|x| x.starts_with(&config.local_cache)) {
+            if !in_use.add(&path)? {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(in_use))
+}
+
+/// Cache the artifacts of the plan and build its composite, unless it
+/// already exists. Returns the path of the composite and its hash, along with
+/// the locks keeping gc from removing them while the request uses them.
+async fn prepare_composite(
+    config: &Config,
+    plan: &mut Plan,
+    uri: &String,
+) -> Result<(PathBuf, String, util::InUse), Error> {
+    let _build = status::BuildGuard::new(uri);
+
+    for _ in 0..USE_ATTEMPTS {
+        cache_artifacts(config, plan, uri).await?;
+        let mut in_use = match use_artifacts(config, plan)? {
+            Some(in_use) => in_use,
+            None => {
+                log::info!("request: {}: artifacts removed meanwhile, caching again", uri);
+                continue;
+            }
+        };
+
+        // Create composite directory
+        let lock = config.composites_cache.join("lock");
+        let node_name = plan.to_composite_path();
+        let composite_path = config.composites_cache.join(&node_name);
+        let path_tmp = config.composites_cache.join(format!("{}.tmp", node_name));
+
+        if !in_use.add(&composite_path)? {
+            log::info!(
+                "request: {}: creating composite path {}",
+                uri,
+                composite_path.display()
+            );
+
+            std::fs::create_dir_all(&config.composites_cache)?;
+
+            let lockfile = std::fs::File::create(&lock)?;
+            lockfile.lock_exclusive()?;
+
+            let _ = std::fs::remove_dir_all(&path_tmp);
+            std::fs::create_dir_all(&path_tmp)?;
+
+            // A failed build leaves nothing behind for the next request to trip on.
+            if let Err(err) = build_composite(config, plan, uri, &path_tmp) {
+                let _ = std::fs::remove_dir_all(&path_tmp);
+                return Err(err);
+            }
+
+            std::fs::rename(path_tmp, &composite_path)?;
+
+            if !in_use.add(&composite_path)? {
+                log::info!("request: {}: composite removed meanwhile, building again", uri);
+                continue;
+            }
+        }
+
+        return Ok((composite_path, node_name, in_use));
+    }
+
+    Err(Error::NotReady(format!("{}: removed by gc while in use", uri)))
+}
+
+async fn service_handle(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>, Error> {
+    // Probes are frequent, so they are not logged.
+    if let Some(rsp) = status::serve(&config, req.uri().path()).await {
+        return rsp;
+    }
+
+    let uri = req.uri().to_string();
+    log::info!("request: {}", uri);
+
+    if let Some(spec) = req.uri().path().strip_prefix(peer::EXPORT_PREFIX) {
+        return export_artifact(&config, &req, spec, &uri).await;
+    }
+
+    if let Some(plan) = req.uri().path().strip_prefix(explain::EXPLAIN_PREFIX) {
+        let text = explain::explain(&config, &format!("/{}", plan), req.uri().query())?;
+        let mut rsp = Response::new(Body::from(text));
+        rsp.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        return Ok(rsp);
+    }
+
+    let mut plan = Plan::from_uri(req.uri().path(), req.uri().query(), &config)?;
+    log::info!("request: plan - {:?}", plan);
+
+    let (composite_path, node_name, in_use) =
+        prepare_composite(&config, &mut plan, &uri).await?;
+
+    if let Some(format) = plan.archive {
+        log::info!("request: streaming {} as {:?}", composite_path.display(), format);
+        let filename = format!("{}-{}", plan.kind.name(), &node_name[..12]);
+        return archive::serve(&composite_path, format, &filename, in_use);
+    }
+
+    if let Kind::Files = plan.kind {
+        let rel = util::percent_decode(plan.sub_uri.trim_start_matches('/'));
+        if !rel.split('/').any(|x| x == "..") && composite_path.join(&rel).is_dir() {
+            let json = req
+                .uri()
+                .query()
+                .is_some_and(|q| q.split('&').any(|x| x == "format=json"));
+
+            log::info!("request: listing {}/{}", composite_path.display(), rel);
+            return listing::serve(&composite_path, &rel, req.uri().path(), json);
+        }
+    }
+
+    let static_ = hyper_staticfile::Static::new(&composite_path);
+
+    let mut req = req;
+    let mut parts = req.uri().clone().into_parts();
+    if let Some(p) = &mut parts.path_and_query {
+        *p = PathAndQuery::from_str(&plan.sub_uri).unwrap();
+    }
+    *req.uri_mut() = Uri::from_parts(parts)?;
+
+    log::info!("request: serving from {}/{}", composite_path.display(), req.uri());
+
+    // The file is open once served, so it can be removed while it's sent.
+    let rsp = static_.serve(req).await?;
+    drop(in_use);
+    Ok(rsp)
+}
+
+async fn service_handle_wrapper(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let uri = req.uri().to_string();
+    let request_id = logging::new_request_id(
+        req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|x| x.to_str().ok()),
+    );
+
+    let mut rsp = logging::REQUEST_ID
+        .scope(request_id.clone(), async move {
+            match service_handle(config, req).await {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("request: {}, failed: {}", uri, err);
+                    status::record_failure(&uri, &err);
+                    let mut rsp = Response::new(Body::from(format!("{:?}", err)));
+                    *rsp.status_mut() = match err {
+                        Error::NotFound(_) => StatusCode::NOT_FOUND,
+                        Error::Conflict(_) => StatusCode::CONFLICT,
+                        Error::NotReady(_) => StatusCode::SERVICE_UNAVAILABLE,
+                        _ => StatusCode::BAD_REQUEST,
+                    };
+                    rsp
+                },
+            }
+        })
+        .await;
+
+    if let Ok(value) = hyper::header::HeaderValue::from_str(&request_id) {
+        rsp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    Ok(rsp)
+}
+
+async fn cache_gitlab_job_artifacts(
+    project_path: PathBuf,
+    lock: PathBuf,
+    path_tmp: PathBuf,
+    job: &JobArtifact,
+    gpipe: &GitlabJobSource,
+    uri: &String,
+    path: PathBuf,
+) -> Result<(), Error> {
+    std::fs::create_dir_all(&project_path)?;
+
+    let lockfile = std::fs::File::create(&lock)?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    log::info!(
+        "request: {}: querying project '{}' job '{}'",
+        uri,
+        job.project,
+        job.job_id
+    );
+
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+    let endpoint = artifacts::JobArtifacts::builder()
+        .project(job.project.clone())
+        .job(job.job_id)
+        .build()
+        .map_err(Error::BuilderError)?;
+
+    log::info!("request: {}: downloading artifacts", uri);
+
+    let policy = http::policy(gpipe.http.as_ref());
+    let artifacts_zip = path_tmp.join("artifacts_zip");
+    gitlab_download(&policy, gpipe, &endpoint, &artifacts_zip).await?;
+
+    log::info!("request: {}: extracting artifacts", uri);
+    {
+        let artifacts_zip = artifacts_zip.display();
+        let path_tmp = path_tmp.display();
+        util::bash(format!("unzip {artifacts_zip} -d {path_tmp}"))?;
+    }
+
+    log::info!("request: {}: placing artifacts", uri);
+
+    std::fs::remove_file(artifacts_zip)?;
+    util::commit_entry(&path_tmp, &path)?;
+
+    Ok(())
+}
+
+/// Keep the job's pipeline, commit and ref for the composite manifests, when
+/// caching its artifacts.
+async fn cache_gitlab_job_json(
+    local_cache: &Path,
+    job: &JobArtifact,
+    gpipe: &GitlabJobSource,
+    uri: &str,
+    gitlab: &mut ClientCache,
+) -> Result<(), Error> {
+    let json_path = job.job_json_path(local_cache);
+    if json_path.exists() {
+        return Ok(());
+    }
+
+    let endpoint = gitlab::api::projects::jobs::Job::builder()
+        .project(job.project.clone())
+        .job(job.job_id)
+        .build()
+        .map_err(|x| Error::BuilderError(x.to_string()))?;
+    let policy = http::policy(gpipe.http.as_ref());
+    let client = gitlab.get(&job.source_name, gpipe).await?;
+    let job_json: serde_json::Value = gitlab_query(&policy, uri, &endpoint, client).await?;
+
+    let json_tmp = util::with_suffix(&json_path, ".tmp");
+    std::fs::write(&json_tmp, serde_json::to_string_pretty(&job_json)?)?;
+    std::fs::rename(&json_tmp, &json_path)?;
+
+    Ok(())
+}
+
+async fn cache_gitlab_package(
+    local_cache: &Path,
+    package: &PackageArtifact,
+    gpipe: &GitlabJobSource,
+    uri: &str,
+    gitlab: &mut ClientCache,
+) -> Result<(), Error> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Package {
+        id: u64,
+        version: String,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct PackageFile {
+        file_name: String,
+    }
+
+    let package_path = package.package_path(local_cache);
+    let path = package_path.join(&package.version);
+    if path.exists() {
+        log::info!("request: {}: package {} exists", uri, path.display());
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&package_path)?;
+
+    let lockfile = std::fs::File::create(package_path.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    log::info!(
+        "request: {}: querying project '{}' package '{}' version '{}'",
+        uri,
+        package.project,
+        package.name,
+        package.version
+    );
+
+    let path_tmp = package_path.join(format!("{}.tmp", package.version));
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    let policy = http::policy(gpipe.http.as_ref());
+    let client = gitlab.get(&package.source_name, gpipe).await?;
+
+    let endpoint = artifacts::ProjectPackages::builder()
+        .project(package.project.clone())
+        .package_name(package.name.clone())
+        .package_type(Some("generic".into()))
+        .build()
+        .map_err(Error::BuilderError)?;
+    let endpoint = gitlab::api::paged(endpoint, gitlab::api::Pagination::All);
+    let packages: Vec<Package> = gitlab_query(&policy, uri, &endpoint, client).await?;
+    let found = packages
+        .into_iter()
+        .find(|x| x.version == package.version)
+        .ok_or_else(|| {
+            Error::NotFound(format!("package {}/{}", package.name, package.version))
+        })?;
+
+    let endpoint = artifacts::PackageFiles::builder()
+        .project(package.project.clone())
+        .package(found.id)
+        .build()
+        .map_err(Error::BuilderError)?;
+    let endpoint = gitlab::api::paged(endpoint, gitlab::api::Pagination::All);
+    let files: Vec<PackageFile> = gitlab_query(&policy, uri, &endpoint, client).await?;
+
+    for file in files.iter() {
+        // The same file name may appear more than once if it was re-uploaded.
+        let local_path = path_tmp.join(util::sanitize_rel_path(&file.file_name));
+        if local_path.exists() || local_path == path_tmp {
+            continue;
+        }
+
+        log::info!("request: {}: downloading package file {}", uri, file.file_name);
+
+        let endpoint = artifacts::GenericPackageFile::builder()
+            .project(package.project.clone())
+            .package_name(package.name.clone())
+            .package_version(package.version.clone())
+            .file_name(file.file_name.clone())
+            .build()
+            .map_err(Error::BuilderError)?;
+        gitlab_download(&policy, gpipe, &endpoint, &local_path).await?;
+    }
+
+    log::info!("request: {}: placing package", uri);
+    util::commit_entry(&path_tmp, &path)?;
+
+    Ok(())
+}
+
+async fn cache_gitlab_release(
+    local_cache: &Path,
+    release: &ReleaseArtifact,
+    gpipe: &GitlabJobSource,
+    uri: &str,
+    gitlab: &mut ClientCache,
+) -> Result<(), Error> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Release {
+        assets: Assets,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Assets {
+        #[serde(default)]
+        links: Vec<Link>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Link {
+        name: String,
+        url: String,
+        direct_asset_url: Option<String>,
+        direct_asset_path: Option<String>,
+    }
+
+    let release_path = release.release_path(local_cache);
+    let path = release_path.join(&release.tag);
+    if path.exists() {
+        log::info!("request: {}: release {} exists", uri, path.display());
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&release_path)?;
+
+    let lockfile = std::fs::File::create(release_path.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    log::info!(
+        "request: {}: querying project '{}' release '{}'",
+        uri,
+        release.project,
+        release.tag
+    );
+
+    let path_tmp = release_path.join(format!("{}.tmp", release.tag));
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    let endpoint = artifacts::ProjectRelease::builder()
+        .project(release.project.clone())
+        .tag(release.tag.clone())
+        .build()
+        .map_err(Error::BuilderError)?;
+    let policy = http::policy(gpipe.http.as_ref());
+    let client = gitlab.get(&release.source_name, gpipe).await?;
+    let found: Release = match gitlab_query(&policy, uri, &endpoint, client).await {
+        Err(Error::HttpStatus(_, status)) if status == reqwest::StatusCode::NOT_FOUND => {
+            return Err(Error::NotFound(format!("release {}", release.tag)));
+        }
+        found => found?,
+    };
+
+    let mut file_names = BTreeSet::new();
+    for link in found.assets.links.iter() {
+        let url = link.direct_asset_url.as_ref().unwrap_or(&link.url);
+        let file_name = release_asset_path(&link.name, link.direct_asset_path.as_deref())
+            .ok_or_else(|| {
+                Error::InvalidResponse(url.clone(), format!("asset name '{}'", link.name))
+            })?;
+        if !file_names.insert(file_name.clone()) {
+            return Err(Error::InvalidResponse(
+                url.clone(),
+                format!("more than one asset named {}", file_name),
+            ));
+        }
+
+        let local_path = path_tmp.join(&file_name);
+        if let Some(parent) = local_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        log::info!("request: {}: downloading release asset {}", uri, url);
+
+        let mut req = http::client_for(&policy).get(url);
+        let on_gitlab = reqwest::Url::parse(url)
+            .map(|x| x.host_str() == Some(gpipe.hostname.as_str()))
+            .unwrap_or(false);
+        if on_gitlab {
+            req = req.header("PRIVATE-TOKEN", gpipe.api_key.expose());
+        }
+
+        http::download_with(&policy, req, &local_path).await?;
+    }
+
+    log::info!("request: {}: placing release", uri);
+    util::commit_entry(&path_tmp, &path)?;
 
     Ok(())
 }
@@ -420,6 +1468,7 @@ async fn cache_static_remote_artifact(
 
     let lockfile = std::fs::File::create(&lock)?;
     lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
 
     log::info!("request: {}: querying SRA {:?} of static remote {:?}", uri, sra, sr);
 
@@ -428,22 +1477,42 @@ async fn cache_static_remote_artifact(
 
     log::info!("request: {}: downloading SRA into {:?}", uri, path_tmp.display());
 
-    let list_url = format!("{}/{}/list.txt", &sr.base_url, sra.subpath);
-    let rsp = reqwest::get(&list_url).await?;
-    if !rsp.status().is_success() {
-        return Err(Error::PlanParse(format!(
-            "error downloading {}: {:?}", list_url, rsp.status()
-        )));
-    }
+    let policy = http::policy(sr.http.as_ref());
+    let client = http::client_for(&policy);
+
+    let files = match sr.mode {
+        RemoteMode::ListTxt => {
+            let list_url = format!("{}/{}/list.txt", &sr.base_url, sra.subpath);
+            let list_txt = http::fetch_with(&policy, client.get(&list_url)).await?;
+            let list_txt = String::from_utf8_lossy(&list_txt);
+            let mut files = vec![];
+
+            for line in list_txt.lines() {
+                // Sanitize the line
+                let parts: Vec<_> = line.split("/")
+                    .filter(|x| *x != "..")
+                    .skip_while(|x| x.is_empty()).collect();
+                let line = parts.join("/");
+                let file_url = format!("{}/{}/{}", &sr.base_url, sra.subpath, line);
+                files.push((line, file_url));
+            }
 
-    let list_txt = rsp.text().await?;
+            files
+        }
+        RemoteMode::Autoindex => {
+            let base_url = format!("{}/{}", &sr.base_url, sra.subpath);
+            log::info!("request: {}: crawling {}", uri, base_url);
+            crawl::list_files(&base_url, sr.max_depth, &policy).await?
+        }
+    };
+
+    let filter = util::PathFilter::new(&sr.include, &sr.exclude)?;
+
+    for (line, file_url) in files {
+        if !filter.matches(&line) {
+            continue;
+        }
 
-    for line in list_txt.lines() {
-        // Sanitize the line
-        let parts: Vec<_> = line.split("/").into_iter()
-            .filter(|x| *x != "..")
-            .skip_while(|x| *x == "").collect();
-        let line = parts.join("/");
         let local_path = path_tmp.join(Path::new(&line));
 
         // Make sure the parent dir exists
@@ -454,21 +1523,13 @@ async fn cache_static_remote_artifact(
         }
 
         // Download the file and write it
-        let file_url = format!("{}/{}/{}", &sr.base_url, sra.subpath, line);
         log::info!("request: {}: downloading {}", uri, file_url);
-        let rsp = reqwest::get(&file_url).await?;
-        if !rsp.status().is_success() {
-            return Err(Error::PlanParse(format!(
-                "error downloading {}: {:?}", file_url, rsp.status()
-            )));
-        }
-
-        let content = rsp.bytes().await?;
+        let content = http::fetch_with(&policy, client.get(&file_url)).await?;
         tokio::fs::write(local_path, content).await?;
     }
 
     log::info!("request: {}: placing SRA", uri);
-    std::fs::rename(path_tmp, path)?;
+    util::commit_entry(&path_tmp, &path)?;
 
     Ok(())
 }
@@ -483,41 +1544,55 @@ impl Main {
                     "{}",
                     serde_yaml::to_string(&Config {
                         listen_addr: "127.0.0.1:4444".into(),
+                        shutdown_deadline: 60,
+                        http: Default::default(),
                         composites_cache: PathBuf::from("/storage/for/repo-composites"),
                         local_cache: PathBuf::from("/storage/for/cached-job-artifacts"),
                         local_source: vec![(
                             "local".into(),
                             LocalPathSource {
                                 root: "/opt/repo/build-output".into(),
+                                filter: None,
                             }
                         )]
                         .into_iter()
                         .collect(),
                         remote_source: vec![].into_iter().collect(),
+                        oci_source: vec![].into_iter().collect(),
+                        s3_source: vec![].into_iter().collect(),
+                        gitea_source: vec![].into_iter().collect(),
+                        jenkins_source: vec![].into_iter().collect(),
+                        speardrive_source: vec![].into_iter().collect(),
                         gitlabs: vec![(
                             "myserver".into(),
                             GitlabJobSource {
-                                api_key: "SomeAPIKEYObtainedFromGitlab".into(),
+                                api_key: Default::default(),
+                                api_key_file: Some("/run/secrets/gitlab-api-key".into()),
+                                api_key_env: None,
                                 hostname: "git.myserver.com".into(),
+                                filter: None,
+                                http: None,
                             }
                         )]
                         .into_iter()
                         .collect()
                     })?
                 );
-                return Err(Error::Help);
+                Err(Error::Help)
             }
-            cmdline::Command::Serve => Ok(Self {
+            cmdline::Command::Serve
+            | cmdline::Command::Gc(_)
+            | cmdline::Command::Build(_)
+            | cmdline::Command::Plan(_)
+            | cmdline::Command::CheckConfig(_) => Ok(Self {
                 config: Self::load_config(opt)?,
+                opt: opt.clone(),
             }),
         }
     }
 
-    fn load_config(opt: &CommandArgs) -> Result<Config, Error> {
-        use ::config as cconfig;
-        use cconfig::TranslationType;
-
-        let config_path = if let Some(config) = &opt.config {
+    fn config_path(opt: &CommandArgs) -> Option<PathBuf> {
+        if let Some(config) = &opt.config {
             Some(config.clone())
         } else {
             if let Ok(path) = std::env::var("SPEARDRIVE_CONFIG_PATH") {
@@ -534,7 +1609,14 @@ impl Main {
                     None
                 }
             }
-        };
+        }
+    }
+
+    fn load_config(opt: &CommandArgs) -> Result<Config, Error> {
+        use ::config as cconfig;
+        use cconfig::TranslationType;
+
+        let config_path = Self::config_path(opt);
 
         let mut settings = cconfig::Config::builder();
         if let Some(config_path) = config_path {
@@ -551,7 +1633,8 @@ impl Main {
 
         let built_config = settings.build()?;
         let config = built_config.try_deserialize();
-        let config = config?;
+        let mut config: Config = config?;
+        config.load_secrets()?;
 
         if opt.dump_config {
             log::info!("{}", serde_yaml::to_string(&config)?);
@@ -561,44 +1644,238 @@ impl Main {
     }
 
     async fn run(&mut self) -> Result<(), Error> {
+        http::configure(&self.config.http);
+
+        match &self.opt.cmd {
+            cmdline::Command::Gc(args) => gc::run(&self.config, args),
+            cmdline::Command::Build(args) => self.build(args).await,
+            cmdline::Command::CheckConfig(args) => check::run(&self.config, args).await,
+            cmdline::Command::Plan(args) => {
+                let (path, query) = Self::split_plan(&args.plan);
+                let config = Arc::new(self.config.clone());
+                print!("{}", explain::explain(&config, &path, query.as_deref())?);
+                Ok(())
+            }
+            _ => self.serve().await,
+        }
+    }
+
+    /// Split a plan given on the command line into the path and query of its
+    /// URL.
+    fn split_plan(plan: &str) -> (String, Option<String>) {
+        let plan = plan.trim_start_matches('/');
+        match plan.split_once('?') {
+            Some((path, query)) => (format!("/{}", path), Some(query.to_owned())),
+            None => (format!("/{}", plan), None),
+        }
+    }
+
+    /// Build the composite of a plan the same way the server does, and copy
+    /// it, or its archive, to the output path.
+    async fn build(&self, args: &cmdline::BuildArgs) -> Result<(), Error> {
+        let (path, query) = Self::split_plan(&args.plan);
+        let uri = match &query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.clone(),
+        };
+
+        let config = Arc::new(self.config.clone());
+        let mut plan = Plan::from_uri(&path, query.as_deref(), &config)?;
+        log::info!("build: plan - {:?}", plan);
+
+        let (composite_path, _, _in_use) = prepare_composite(&config, &mut plan, &uri).await?;
+
+        let out = std::env::current_dir()?.join(&args.out);
+        if let Some(format) = plan.archive {
+            log::info!("build: writing {} as {:?}", out.display(), format);
+            archive::write(&composite_path, format, &out)?;
+        } else {
+            log::info!("build: placing {}", out.display());
+            std::fs::create_dir_all(&out)?;
+            let composite_path = composite_path.display();
+            let out = out.display();
+            util::bash(format!(
+                "cp -al {composite_path}/. {out}/ || cp -a {composite_path}/. {out}/"
+            ))?;
+        }
+
+        println!("{}", out.display());
+        Ok(())
+    }
+
+    /// Reload the config on SIGHUP, or when the config file changes.
+    async fn watch_config(opt: CommandArgs, state: Arc<RwLock<Arc<Config>>>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let path = Self::config_path(&opt);
+        let modified = || {
+            let metadata = std::fs::metadata(path.as_ref()?).ok()?;
+            metadata.modified().ok()
+        };
+        let mut last_modified = modified();
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::error!("config: cannot handle SIGHUP: {}", err);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    log::info!("config: reloading on SIGHUP");
+                }
+                _ = interval.tick() => {
+                    let current = modified();
+                    if current == last_modified {
+                        continue;
+                    }
+                    last_modified = current;
+                    log::info!("config: reloading on change of the config file");
+                }
+            }
+
+            // Loading reads the config and secret files, and validating
+            // probes the caches and resolves the listen address, all of which
+            // block, so they stay off the threads serving the requests.
+            let (opt, state) = (opt.clone(), state.clone());
+            let reload = tokio::task::spawn_blocking(move || Self::reload_config(&opt, &state));
+            if let Err(err) = reload.await {
+                log::error!("config: keeping the current config, failed to reload: {}", err);
+            }
+        }
+    }
+
+    /// Swap in the new config if it loads and validates, keeping the current
+    /// one otherwise.
+    fn reload_config(opt: &CommandArgs, state: &RwLock<Arc<Config>>) {
+        let config = match Self::load_config(opt) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("config: keeping the current config, failed to load: {}", err);
+                return;
+            }
+        };
+
+        if let Err(failures) = check::validate(&config) {
+            for failure in failures {
+                log::error!("config: {}", failure);
+            }
+            log::error!("config: keeping the current config, the new one is invalid");
+            return;
+        }
+
+        let mut current = state.write().unwrap();
+        if current.listen_addr != config.listen_addr {
+            log::warn!("config: listen-addr changes only apply after a restart");
+        }
+        http::configure(&config.http);
+        *current = Arc::new(config);
+        log::info!("config: reloaded");
+    }
+
+    async fn serve(&mut self) -> Result<(), Error> {
         let addr = match self.config.listen_addr.to_socket_addrs() {
             Ok(addr) => addr.collect::<Vec<_>>().pop().unwrap(),
             Err(err) => return Err(Error::InvalidAddress(format!("{:?}", err))),
         };
 
-        let config = Arc::new(self.config.clone());
+        // Requests take the current config when they start, so a reload
+        // does not affect the ones in flight.
+        let state = Arc::new(RwLock::new(Arc::new(self.config.clone())));
+        tokio::spawn(Self::watch_config(self.opt.clone(), state.clone()));
+        let deadline_state = state.clone();
+
+        status::init();
+
         let make_svc = make_service_fn(move |_conn| {
-            let config = config.clone();
-            let service_handler = move |req| service_handle_wrapper(config.clone(), req);
+            let state = state.clone();
+            let service_handler = move |req| {
+                let config = state.read().unwrap().clone();
+                service_handle_wrapper(config, req)
+            };
             async move { Ok::<_, Infallible>(service_fn(service_handler)) }
         });
         let bound = hyper::Server::bind(&addr);
 
+        // Walking the caches takes a while, so it doesn't hold up serving.
+        let config = self.config.clone();
+        tokio::task::spawn_blocking(move || match gc::remove_stale_tmps(&config) {
+            Ok(0) => {}
+            Ok(removed) => log::info!("removed {} stale builds", removed),
+            Err(err) => log::error!("removing stale builds: {}", err),
+        });
+
         log::info!("waiting for requests");
 
-        let server = bound.serve(make_svc);
+        // Stop accepting connections on SIGTERM or SIGINT, and let the
+        // requests in flight finish, up to the deadline.
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let server = bound.serve(make_svc).with_graceful_shutdown(async move {
+            Self::shutdown_signal().await;
+            let deadline = Duration::from_secs(deadline_state.read().unwrap().shutdown_deadline);
+            log::info!("shutdown: waiting up to {:?} for requests in flight", deadline);
+            let _ = shutdown_tx.send(deadline);
+        });
 
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+        tokio::select! {
+            res = server => {
+                if let Err(e) = res {
+                    eprintln!("server error: {}", e);
+                }
+            }
+            _ = async move {
+                match shutdown_rx.await {
+                    Ok(deadline) => tokio::time::sleep(deadline).await,
+                    Err(_) => std::future::pending().await,
+                }
+            } => {
+                log::warn!("shutdown: deadline passed, abandoning requests in flight");
+            }
         }
 
         Ok(())
     }
+
+    async fn shutdown_signal() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(err) => {
+                log::error!("shutdown: cannot handle SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
 }
 
 fn main_wrap() -> Result<(), Error> {
     let opt = CommandArgs::from_args();
 
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(3)
-        .build()?
-        .block_on(async {
-            match Main::new(&opt).await {
-                Err(err) => Err(err),
-                Ok(mut main) => main.run().await,
-            }
-        })?;
+        .build()?;
+    let res = runtime.block_on(async {
+        match Main::new(&opt).await {
+            Err(err) => Err(err),
+            Ok(mut main) => main.run().await,
+        }
+    });
+
+    // Don't wait for requests abandoned at shutdown. Their leftovers are
+    // removed on the next startup.
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    res?;
 
     Ok(())
 }
@@ -612,3 +1889,341 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Arc<Config> {
+        Arc::new(serde_yaml::from_str(
+            r#"
+listen-addr: 127.0.0.1:0
+composites-cache: /nonexistent/composites
+local-cache: /nonexistent/local
+gitlabs:
+  gl:
+    hostname: gitlab.example.com
+local-source:
+  loc:
+    root: /nonexistent/loc
+  filtered:
+    root: /nonexistent/filtered
+    filter: 'out/**/*.rpm'
+oci-source:
+  reg:
+    registry-url: https://registry.example.com
+s3-source:
+  bucket:
+    endpoint: https://s3.example.com
+    bucket: builds
+gitea-source:
+  gt:
+    base-url: https://gitea.example.com
+jenkins-source:
+  ci:
+    base-url: https://jenkins.example.com
+remote-source:
+  rem:
+    base-url: https://files.example.com
+speardrive-source:
+  dc1:
+    base-url: https://dc1.example.com/
+"#,
+        )
+        .unwrap())
+    }
+
+    #[test]
+    fn plan_kinds() {
+        let config = test_config();
+        let plan = Plan::from_uri("/loc/a/-/loc/b/-/rpm/repodata/repomd.xml", None, &config)
+            .unwrap();
+        assert_eq!(plan.artifacts.len(), 2);
+        assert!(matches!(plan.kind, Kind::RPM));
+        assert_eq!(plan.sub_uri, "/repodata/repomd.xml");
+
+        let plan = Plan::from_uri("/loc/a/-/pacman/myrepo/myrepo.db", None, &config).unwrap();
+        assert!(matches!(&plan.kind, Kind::Pacman(name) if name == "myrepo"));
+        assert_eq!(plan.sub_uri, "/myrepo.db");
+
+        assert!(Plan::from_uri("/loc/a/-/pacman/.bad", None, &config).is_err());
+        assert!(Plan::from_uri("/status/a/-/rpm", None, &config).is_err());
+        assert!(matches!(
+            Plan::from_uri("/nosuch/a/-/rpm", None, &config),
+            Err(Error::UnknownSource(_))
+        ));
+        assert!(Plan::from_uri("/rpm", None, &config).is_err());
+    }
+
+    #[test]
+    fn files_plans() {
+        let config = test_config();
+        let plan = Plan::from_uri("/loc/a/-/files/sub/x.txt", None, &config).unwrap();
+        assert!(matches!(plan.kind, Kind::Files));
+        assert_eq!(plan.sub_uri, "/sub/x.txt");
+
+        let plan = Plan::from_uri("/loc/a/-/files", None, &config).unwrap();
+        assert!(matches!(plan.kind, Kind::Files));
+    }
+
+    #[test]
+    fn archive_plans() {
+        let config = test_config();
+        let plan = Plan::from_uri("/loc/a/-/rpm/repodata/repomd.xml", None, &config).unwrap();
+        assert_eq!(plan.archive, None);
+
+        let plan = Plan::from_uri("/loc/a/-/rpm.tar.zst", None, &config).unwrap();
+        assert!(matches!(plan.kind, Kind::RPM));
+        assert_eq!(plan.archive, Some(archive::Format::TarZst));
+
+        let plan = Plan::from_uri("/loc/a/-/pacman/myrepo.zip", None, &config).unwrap();
+        assert!(matches!(&plan.kind, Kind::Pacman(name) if name == "myrepo"));
+        assert!(plan.archive.is_some());
+    }
+
+    #[test]
+    fn rpm_plan_options() {
+        let config = test_config();
+        let plan = Plan::from_uri("/loc/a/-/rpm", Some("arch=x86_64&dedup=last"), &config)
+            .unwrap();
+        assert_eq!(plan.rpm.arch, vec!["x86_64"]);
+        assert!(Plan::from_uri("/loc/a/-/files", Some("arch=x86_64"), &config).is_err());
+    }
+
+    #[test]
+    fn oci_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/reg/group/app/v1.0/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[..] {
+            [Artifact::Oci(oa)] => {
+                assert_eq!(oa.repo, "group/app");
+                assert_eq!(oa.reference, "v1.0");
+                assert_eq!(oa.digest, None);
+            }
+            other => panic!("unexpected artifacts {:?}", other),
+        }
+
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let plan = Plan::from_uri(&format!("/reg/app/{}:el9/-/rpm", digest), None, &config)
+            .unwrap();
+        assert_eq!(plan.filters, vec![Some("el9".to_owned())]);
+        let expected = Some(&digest);
+        assert!(matches!(&plan.artifacts[0], Artifact::Oci(oa) if oa.digest.as_ref() == expected));
+
+        let plan = Plan::from_uri(&format!("/reg/app/{}/-/rpm", digest), None, &config).unwrap();
+        assert_eq!(plan.filters, vec![None]);
+
+        // Only a whole digest component keeps its colon.
+        let plan = Plan::from_uri("/reg/app/build-sha256:out/**/-/rpm", None, &config).unwrap();
+        assert_eq!(plan.filters, vec![Some("out/**".to_owned())]);
+        assert!(matches!(&plan.artifacts[0], Artifact::Oci(oa) if oa.reference == "build-sha256"));
+        let plan = Plan::from_uri("/loc/build-sha256:out/**/-/rpm", None, &config).unwrap();
+        assert_eq!(plan.filters, vec![Some("out/**".to_owned())]);
+    }
+
+    #[test]
+    fn s3_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/bucket/nightly//x86_64/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[..] {
+            [Artifact::S3(s3a)] => assert_eq!(s3a.prefix, "nightly/x86_64"),
+            other => panic!("unexpected artifacts {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gitea_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/gt/owner/repo/12/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[..] {
+            [Artifact::GiteaRun(run)] => {
+                assert_eq!((run.owner.as_str(), run.repo.as_str()), ("owner", "repo"));
+                assert_eq!(run.run_id, 12);
+            }
+            other => panic!("unexpected artifacts {:?}", other),
+        }
+        assert!(Plan::from_uri("/gt/owner/12/-/rpm", None, &config).is_err());
+    }
+
+    #[test]
+    fn jenkins_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/ci/products/foo/lastSuccessfulBuild/-/rpm", None, &config)
+            .unwrap();
+        match &plan.artifacts[..] {
+            [Artifact::JenkinsBuild(jba)] => {
+                assert_eq!(jba.job_path, "products/foo");
+                assert_eq!(jba.build_number, None);
+            }
+            other => panic!("unexpected artifacts {:?}", other),
+        }
+        assert!(Plan::from_uri("/ci/foo/nope/-/rpm", None, &config).is_err());
+    }
+
+    #[test]
+    fn remote_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/rem/a/b/-/loc/build-1/-/files", None, &config).unwrap();
+        match &plan.artifacts[..] {
+            [Artifact::Remote(remote), Artifact::Local(local)] => {
+                assert_eq!(remote.subpath, "a/b");
+                assert_eq!(local.key, PathBuf::from("build-1"));
+            }
+            other => panic!("unexpected artifacts {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spec_filters() {
+        let config = test_config();
+        let plan = Plan::from_uri("/gl/group/proj/323:rpms/el9/**/*.rpm/-/rpm", None, &config)
+            .unwrap();
+        assert_eq!(plan.specs, vec!["gl/group/proj/323"]);
+        assert_eq!(plan.filters, vec![Some("rpms/el9/**/*.rpm".to_owned())]);
+
+        let plan = Plan::from_uri("/loc/k/-/filtered/k/-/filtered/k:sub/-/files", None, &config)
+            .unwrap();
+        assert_eq!(
+            plan.filters,
+            vec![None, Some("out/**/*.rpm".to_owned()), Some("sub".to_owned())]
+        );
+
+        // An empty filter selects everything.
+        let plan = Plan::from_uri("/gl/group/proj/323:/-/rpm", None, &config).unwrap();
+        assert_eq!(plan.filters, vec![None]);
+    }
+
+    #[test]
+    fn gitlab_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/gl/group/proj/323/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[0] {
+            Artifact::GitlabJob(job) => {
+                assert_eq!(job.project, "group/proj");
+                assert_eq!(job.job_id, 323);
+            }
+            other => panic!("unexpected artifact {:?}", other),
+        }
+
+        // A project named `package` is still a project.
+        let plan = Plan::from_uri("/gl/group/package/foo/12/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[0] {
+            Artifact::GitlabJob(job) => {
+                assert_eq!(job.project, "group/package/foo");
+                assert_eq!(job.job_id, 12);
+            }
+            other => panic!("unexpected artifact {:?}", other),
+        }
+
+        let plan = Plan::from_uri("/gl/group/proj/-package/foo/1.2.3/-/rpm", None, &config)
+            .unwrap();
+        match &plan.artifacts[0] {
+            Artifact::GitlabPackage(package) => {
+                assert_eq!(package.project, "group/proj");
+                assert_eq!(package.name, "foo");
+                assert_eq!(package.version, "1.2.3");
+                assert_eq!(
+                    plan.artifacts[0].local_path(&config),
+                    Some(PathBuf::from("/nonexistent/local/gl/-package/group/proj/foo/1.2.3"))
+                );
+            }
+            other => panic!("unexpected artifact {:?}", other),
+        }
+
+        assert!(Plan::from_uri("/gl/group/proj/-package/foo/1.2?3/-/rpm", None, &config).is_err());
+        assert!(Plan::from_uri("/gl/group/proj/abc/-/rpm", None, &config).is_err());
+    }
+
+    #[test]
+    fn release_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/gl/group/proj/-release/v1.0/-/rpm", None, &config).unwrap();
+        match &plan.artifacts[0] {
+            Artifact::GitlabRelease(release) => {
+                assert_eq!(release.project, "group/proj");
+                assert_eq!(release.tag, "v1.0");
+                assert_eq!(
+                    plan.artifacts[0].local_path(&config),
+                    Some(PathBuf::from("/nonexistent/local/gl/-release/group/proj/v1.0"))
+                );
+            }
+            other => panic!("unexpected artifact {:?}", other),
+        }
+
+        // A project named `release` is still a project.
+        let plan = Plan::from_uri("/gl/group/release/7/-/rpm", None, &config).unwrap();
+        assert!(matches!(&plan.artifacts[0], Artifact::GitlabJob(job) if job.job_id == 7));
+    }
+
+    #[test]
+    fn release_asset_paths() {
+        assert_eq!(
+            release_asset_path("foo", Some("/bin/foo-1.0.x86_64.rpm")),
+            Some("bin/foo-1.0.x86_64.rpm".to_owned())
+        );
+        assert_eq!(release_asset_path("foo.rpm", None), Some("foo.rpm".to_owned()));
+        assert_eq!(release_asset_path("../a/b.rpm", Some("")), Some("a_b.rpm".to_owned()));
+        assert_eq!(release_asset_path("..", Some("/../")), None);
+    }
+
+    #[test]
+    fn pacman_packages() {
+        assert!(is_pacman_package("foo-1.0-1-x86_64.pkg.tar.zst"));
+        assert!(is_pacman_package("foo-1.0-1-any.pkg.tar.xz"));
+        assert!(!is_pacman_package("foo-1.0-1-x86_64.pkg.tar.zst.sig"));
+        assert!(!is_pacman_package("foo.db.tar.gz"));
+        assert!(!is_pacman_package(".pkg.tar.zst"));
+        assert!(!is_pacman_package("foo.pkg.tar."));
+    }
+
+    #[test]
+    fn pacman_links() {
+        let root = std::env::temp_dir().join(format!("speardrive-pacman-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let write = |rel: &str, data: &str| {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        };
+        let specs = vec!["a/1".to_owned(), "b/2".to_owned()];
+
+        write("0/a/foo-1-1-any.pkg.tar.zst", "foo");
+        write("0/a/foo-1-1-any.pkg.tar.zst.sig", "sig");
+        write("1/b/foo-1-1-any.pkg.tar.zst", "foo");
+        write("1/b/readme.txt", "readme");
+        let packages = link_pacman_packages(&root, &specs).unwrap();
+        assert_eq!(packages, vec!["foo-1-1-any.pkg.tar.zst"]);
+        assert!(root.join("foo-1-1-any.pkg.tar.zst.sig").exists());
+        assert!(!root.join("readme.txt").exists());
+
+        let _ = std::fs::remove_dir_all(&root);
+        write("0/a/foo-1-1-any.pkg.tar.zst", "foo");
+        write("1/b/foo-1-1-any.pkg.tar.zst", "other foo");
+        match link_pacman_packages(&root, &specs) {
+            Err(Error::Conflict(msg)) => assert!(msg.contains("a/1") && msg.contains("b/2")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn peer_specs() {
+        let config = test_config();
+        let plan = Plan::from_uri("/dc1/gl/group/proj/323/-/rpm", None, &config).unwrap();
+        assert_eq!(plan.artifacts.len(), 1);
+        match &plan.artifacts[0] {
+            Artifact::Peer(pa) => {
+                assert_eq!(pa.source_name, "dc1");
+                assert_eq!(pa.spec, "gl/group/proj/323");
+                assert_eq!(pa.identity, None);
+            }
+            other => panic!("unexpected artifact {:?}", other),
+        }
+
+        // Peer artifacts are only placed once the peer tells what they are.
+        assert_eq!(plan.artifacts[0].local_path(&config), None);
+
+        assert!(Plan::from_uri("/dc1/-/rpm", None, &config).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, util};
+
+/// The provenance of a composite, kept in its root.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    pub url: String,
+    pub created: String,
+    pub sources: Vec<Source>,
+    pub files: Vec<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Source {
+    pub spec: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// The artifact's entry in the local cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_path: Option<PathBuf>,
+    /// When the artifact was downloaded into the local cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitlab_job: Option<GitlabJob>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GitlabJob {
+    pub id: u64,
+    pub pipeline: Option<u64>,
+    pub sha: Option<String>,
+    #[serde(rename = "ref")]
+    pub ref_: Option<String>,
+    pub finished_at: Option<String>,
+    pub web_url: Option<String>,
+}
+
+impl GitlabJob {
+    /// Pick the fields of a job as returned by the GitLab API.
+    pub fn from_api(job: &serde_json::Value) -> Option<Self> {
+        let string = |x: &serde_json::Value| x.as_str().map(|x| x.to_owned());
+
+        Some(Self {
+            id: job["id"].as_u64()?,
+            pipeline: job["pipeline"]["id"].as_u64(),
+            sha: string(&job["commit"]["id"]).or_else(|| string(&job["pipeline"]["sha"])),
+            ref_: string(&job["ref"]),
+            finished_at: string(&job["finished_at"]),
+            web_url: string(&job["web_url"]),
+        })
+    }
+
+    /// Read the job saved next to its cached artifacts.
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = std::fs::read(path).ok()?;
+        let job: serde_json::Value = serde_json::from_slice(&content).ok()?;
+        Self::from_api(&job)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct File {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Index into `sources`, unless the file was generated for the composite.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<usize>,
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl Manifest {
+    /// Describe every file of a composite built under `root`, where the
+    /// files of each source are placed under `<index>/`. Files hardlinked
+    /// elsewhere in the composite are attributed to their source too.
+    pub fn build(root: &Path, url: &str, sources: Vec<Source>) -> Result<Self, Error> {
+        let files = util::walk_files(root)?;
+
+        let source_of = |rel: &Path| -> Option<usize> {
+            let first = rel.iter().next()?.to_str()?;
+            first.parse().ok().filter(|x| *x < sources.len() && rel.iter().count() > 1)
+        };
+
+        let mut inodes = HashMap::new();
+        for rel in files.iter() {
+            if let Some(idx) = source_of(rel) {
+                let metadata = std::fs::metadata(root.join(rel))?;
+                inodes.entry((metadata.dev(), metadata.ino())).or_insert(idx);
+            }
+        }
+
+        let mut entries = vec![];
+        for rel in files.iter() {
+            if rel == Path::new(MANIFEST_FILE) {
+                continue;
+            }
+
+            let path = root.join(rel);
+            let metadata = std::fs::metadata(&path)?;
+            entries.push(File {
+                path: rel.to_string_lossy().into_owned(),
+                size: metadata.len(),
+                sha256: sha256_file(&path)?,
+                source: source_of(rel)
+                    .or_else(|| inodes.get(&(metadata.dev(), metadata.ino())).copied()),
+            });
+        }
+
+        Ok(Self {
+            url: url.to_owned(),
+            created: chrono::Utc::now().to_rfc3339(),
+            sources,
+            files: entries,
+        })
+    }
+
+    pub fn write(&self, root: &Path) -> Result<(), Error> {
+        std::fs::write(root.join(MANIFEST_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use regex::Regex;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::OciSource, error::Error, http, manifest::sha256_file, util};
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+                              application/vnd.docker.distribution.manifest.v2+json";
+
+/// Media types of manifests listing other manifests rather than layers.
+const INDEX_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Layer annotation holding the file name, as set by ORAS.
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Layer annotation by which ORAS marks a directory pushed as a tarball.
+const UNPACK_ANNOTATION: &str = "io.deis.oras.content.unpack";
+
+#[derive(Debug, Clone)]
+pub struct OciArtifact {
+    pub source_name: String,
+    pub repo: String,
+    pub reference: String,
+    /// The manifest digest the reference resolved to.
+    pub digest: Option<String>,
+}
+
+impl OciArtifact {
+    pub fn new(source_name: &str, repo: String, reference: &str) -> Result<Self, Error> {
+        lazy_static::lazy_static! {
+            static ref REPO_RE: Regex =
+                Regex::new("^[a-z0-9]+([._-][a-z0-9]+)*(/[a-z0-9]+([._-][a-z0-9]+)*)*$").unwrap();
+            static ref REF_RE: Regex =
+                Regex::new("^([A-Za-z0-9_][A-Za-z0-9_.-]{0,127}|sha256:[a-f0-9]{64})$").unwrap();
+        }
+
+        if !REPO_RE.is_match(&repo) {
+            return Err(Error::PlanParse(format!("{} invalid OCI repository name", repo)));
+        }
+        if !REF_RE.is_match(reference) {
+            return Err(Error::PlanParse(format!("{} invalid OCI tag or digest", reference)));
+        }
+
+        Ok(Self {
+            source_name: source_name.to_owned(),
+            repo,
+            reference: reference.to_owned(),
+            digest: reference.starts_with("sha256:").then(|| reference.to_owned()),
+        })
+    }
+}
+
+/// Split the `:<filter>` suffix off a spec whose last component may be a
+/// `sha256:<hex>` digest, holding a colon of its own.
+pub fn split_filter(item: &str) -> Option<(&str, Option<&str>)> {
+    lazy_static::lazy_static! {
+        static ref DIGEST_SPEC_RE: Regex =
+            Regex::new("^([^:]*/sha256:[a-f0-9]{64})(?::(.*))?$").unwrap();
+    }
+
+    let captures = DIGEST_SPEC_RE.captures(item)?;
+    let spec = captures.get(1)?.as_str();
+    Some((spec, captures.get(2).map(|x| x.as_str())))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    #[serde(default)]
+    media_type: Option<String>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+impl Manifest {
+    /// The layers with the relative paths to place them at. Image indexes
+    /// have no layers of their own, so they are refused, as are layers that
+    /// would be placed at the same path.
+    fn layer_paths(&self, what: &str) -> Result<Vec<(&Descriptor, String)>, Error> {
+        let is_index = self.media_type.as_deref().is_some_and(|x| INDEX_TYPES.contains(&x));
+        if is_index || !self.manifests.is_empty() {
+            return Err(Error::InvalidResponse(
+                what.to_owned(),
+                "an image index rather than an artifact manifest".to_owned(),
+            ));
+        }
+
+        let mut names = HashSet::new();
+        let mut layers = vec![];
+        for layer in self.layers.iter() {
+            let name = match layer.annotations.get(TITLE_ANNOTATION) {
+                Some(title) => util::sanitize_rel_path(title),
+                None => layer.digest.replace(':', "-"),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            if !names.insert(name.clone()) {
+                return Err(Error::InvalidResponse(
+                    what.to_owned(),
+                    format!("more than one layer titled {}", name),
+                ));
+            }
+            layers.push((layer, name));
+        }
+
+        Ok(layers)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+/// A registry session, handling basic authentication and the bearer token
+/// challenge of the distribution spec.
+struct Registry<'a> {
+    client: Client,
+    policy: http::Policy,
+    source: &'a OciSource,
+    token: Option<String>,
+}
+
+impl<'a> Registry<'a> {
+    fn new(source: &'a OciSource) -> Self {
+        let policy = http::policy(None);
+        Self {
+            client: http::client_for(&policy),
+            policy,
+            source,
+            token: None,
+        }
+    }
+
+    fn url(&self, repo: &str, rest: &str) -> String {
+        format!("{}/v2/{}/{}", self.source.registry_url.trim_end_matches('/'), repo, rest)
+    }
+
+    fn authorize(&self, req: RequestBuilder) -> RequestBuilder {
+        if let Some(token) = &self.token {
+            req.bearer_auth(token)
+        } else if let Some(username) = &self.source.username {
+            req.basic_auth(username, self.source.password.as_ref().map(|x| x.expose()))
+        } else {
+            req
+        }
+    }
+
+    async fn get(&mut self, url: &str, accept: &str) -> Result<Response, Error> {
+        // The challenge comes as an unsuccessful response, so only retry the
+        // failures that can't be one.
+        let this = &*self;
+        let rsp = http::retry(&self.policy, url, || async move {
+            let req = this.client.get(url).header(header::ACCEPT, accept);
+            let rsp = http::timeout(&this.policy, url, async {
+                Ok(this.authorize(req).send().await?)
+            })
+            .await?;
+            if rsp.status().is_server_error() || rsp.status() == StatusCode::TOO_MANY_REQUESTS {
+                return Err(Error::HttpStatus(url.to_owned(), rsp.status()));
+            }
+            Ok(rsp)
+        })
+        .await?;
+
+        if rsp.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+            if let Some(challenge) = rsp.headers().get(header::WWW_AUTHENTICATE) {
+                let challenge = challenge.to_str().unwrap_or("").to_owned();
+                if challenge.starts_with("Bearer ") {
+                    self.token = Some(self.fetch_token(&challenge).await?);
+                    let req = self.client.get(url).header(header::ACCEPT, accept);
+                    return http::send_with(&self.policy, self.authorize(req)).await;
+                }
+            }
+        }
+
+        if !rsp.status().is_success() {
+            return Err(Error::HttpStatus(url.to_owned(), rsp.status()));
+        }
+
+        Ok(rsp)
+    }
+
+    /// Download a blob into a file. The manifest is fetched first, so the
+    /// token of any challenge is already there.
+    async fn download(&self, url: &str, path: &Path) -> Result<(), Error> {
+        let req = self.authorize(self.client.get(url));
+        http::download_with(&self.policy, req, path).await
+    }
+
+    async fn fetch_token(&self, challenge: &str) -> Result<String, Error> {
+        lazy_static::lazy_static! {
+            static ref PARAM_RE: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+        }
+
+        let params: HashMap<_, _> = PARAM_RE
+            .captures_iter(challenge)
+            .map(|c| (c[1].to_owned(), c[2].to_owned()))
+            .collect();
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| Error::InvalidResponse(challenge.to_owned(), "no realm".to_owned()))?;
+
+        let query: Vec<_> = ["service", "scope"]
+            .iter()
+            .filter_map(|k| params.get(*k).map(|v| (*k, v.as_str())))
+            .collect();
+        let mut req = self.client.get(realm.as_str()).query(&query);
+        if let Some(username) = &self.source.username {
+            req = req.basic_auth(username, self.source.password.as_ref().map(|x| x.expose()));
+        }
+
+        let body = http::fetch_with(&self.policy, req).await?;
+        let token: Token = serde_json::from_slice(&body)?;
+        Ok(token.token)
+    }
+}
+
+/// The file of the repository's cache directory recording the digest a tag
+/// last resolved to. Repository names can't hold a `:`, so it can't be taken
+/// for a nested repository.
+fn tag_record(repo_path: &Path, tag: &str) -> PathBuf {
+    repo_path.join(format!("tag:{}", tag))
+}
+
+/// The digest the tag last resolved to, if its artifact is still cached.
+fn cached_tag_digest(repo_path: &Path, tag: &str) -> Option<String> {
+    let digest = std::fs::read_to_string(tag_record(repo_path, tag)).ok()?;
+    let digest = digest.trim();
+    (digest.starts_with("sha256:") && repo_path.join(digest).exists()).then(|| digest.to_owned())
+}
+
+/// Record the digest the tag resolved to. Concurrent requests may race to
+/// record it, so a failure only loses the fallback.
+fn record_tag_digest(repo_path: &Path, tag: &str, digest: &str, uri: &str) {
+    let record = tag_record(repo_path, tag);
+    let record_tmp = util::with_suffix(&record, &format!(".{}.new", std::process::id()));
+    let recorded = std::fs::create_dir_all(repo_path)
+        .and_then(|_| std::fs::write(&record_tmp, digest))
+        .and_then(|_| std::fs::rename(&record_tmp, &record));
+    if let Err(err) = recorded {
+        log::warn!("request: {}: recording {} as {}: {}", uri, tag, digest, err);
+        let _ = std::fs::remove_file(record_tmp);
+    }
+}
+
+/// Fetch the manifest of the reference, returning its digest and body.
+async fn fetch_manifest(
+    registry: &mut Registry<'_>,
+    oa: &OciArtifact,
+) -> Result<(String, Vec<u8>), Error> {
+    let manifest_url = registry.url(&oa.repo, &format!("manifests/{}", oa.reference));
+    let rsp = registry.get(&manifest_url, MANIFEST_TYPES).await?;
+    let header_digest = rsp
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_owned());
+    let body = http::read_body(&registry.policy, rsp).await?;
+    let digest = match (&oa.digest, header_digest) {
+        (Some(digest), _) => digest.clone(),
+        (None, Some(digest)) => digest,
+        (None, None) => format!("sha256:{}", hex::encode(Sha256::digest(&body))),
+    };
+    Ok((digest, body))
+}
+
+/// Resolve the artifact's manifest digest, and download its layers into
+/// `<local_cache>/<source>/<repo>/<digest>` unless they are already there.
+/// Digests already cached are used without asking the registry, and tags
+/// fall back to the digest they last resolved to if the registry can't be
+/// reached.
+pub async fn cache_artifact(
+    local_cache: &Path,
+    oa: &mut OciArtifact,
+    os: &OciSource,
+    uri: &str,
+) -> Result<(), Error> {
+    let repo_path = local_cache.join(&oa.source_name).join(&oa.repo);
+    if let Some(digest) = &oa.digest {
+        let path = repo_path.join(digest);
+        if path.exists() {
+            log::info!("request: {}: OCI artifact {} exists", uri, path.display());
+            return Ok(());
+        }
+    }
+
+    let mut registry = Registry::new(os);
+
+    log::info!("request: {}: fetching OCI manifest {}:{}", uri, oa.repo, oa.reference);
+
+    let (digest, body) = match fetch_manifest(&mut registry, oa).await {
+        Ok(fetched) => fetched,
+        Err(err) if oa.digest.is_none() && http::is_transient(&err) => {
+            match cached_tag_digest(&repo_path, &oa.reference) {
+                Some(digest) => {
+                    log::warn!(
+                        "request: {}: {}, using {} as last resolved for {}",
+                        uri,
+                        err,
+                        digest,
+                        oa.reference
+                    );
+                    oa.digest = Some(digest);
+                    return Ok(());
+                }
+                None => return Err(err),
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    let is_tag = oa.digest.is_none();
+    oa.digest = Some(digest.clone());
+
+    let path = repo_path.join(&digest);
+    if path.exists() {
+        log::info!("request: {}: OCI artifact {} exists", uri, path.display());
+        if is_tag {
+            record_tag_digest(&repo_path, &oa.reference, &digest, uri);
+        }
+        return Ok(());
+    }
+
+    let manifest: Manifest = serde_json::from_slice(&body)?;
+    let layers = manifest.layer_paths(&format!("{}:{}", oa.repo, oa.reference))?;
+
+    std::fs::create_dir_all(&repo_path)?;
+
+    let lockfile = std::fs::File::create(repo_path.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    util::begin_entry(&path)?;
+
+    let path_tmp = repo_path.join(format!("{}.tmp", digest));
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    for (layer, name) in layers {
+        log::info!("request: {}: downloading layer {} as {}", uri, layer.digest, name);
+
+        let local_path = path_tmp.join(&name);
+        if let Some(parent) = local_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let unpack = layer.annotations.get(UNPACK_ANNOTATION).map(|x| x.as_str()) == Some("true");
+        let download_path = if unpack {
+            path_tmp.join(format!("{}.tar.gz", layer.digest.replace(':', "-")))
+        } else {
+            local_path.clone()
+        };
+
+        let blob_url = registry.url(&oa.repo, &format!("blobs/{}", layer.digest));
+        registry.download(&blob_url, &download_path).await?;
+        if let Some(expected) = layer.digest.strip_prefix("sha256:") {
+            if sha256_file(&download_path)? != expected {
+                return Err(Error::InvalidResponse(blob_url, "digest mismatch".to_owned()));
+            }
+        }
+
+        if unpack {
+            std::fs::create_dir_all(&local_path)?;
+            {
+                let tarball = download_path.display();
+                let local_path = local_path.display();
+                util::bash(format!("tar -xzf {tarball} -C {local_path}"))?;
+            }
+            std::fs::remove_file(download_path)?;
+        }
+    }
+
+    log::info!("request: {}: placing OCI artifact", uri);
+    util::commit_entry(&path_tmp, &path)?;
+    if is_tag {
+        record_tag_digest(&repo_path, &oa.reference, &digest, uri);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str =
+        "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn references() {
+        let oa = OciArtifact::new("reg", "group/app".to_owned(), "v1.2_rc-3").unwrap();
+        assert_eq!(oa.repo, "group/app");
+        assert_eq!(oa.reference, "v1.2_rc-3");
+        assert_eq!(oa.digest, None);
+
+        let oa = OciArtifact::new("reg", "app".to_owned(), DIGEST).unwrap();
+        assert_eq!(oa.digest.as_deref(), Some(DIGEST));
+
+        for reference in ["", ".hidden", "-x", "a/b", "sha256:abc", "sha512:00", "a:b"] {
+            assert!(OciArtifact::new("reg", "app".to_owned(), reference).is_err(), "{}", reference);
+        }
+        assert!(OciArtifact::new("reg", "x".to_owned(), &"a".repeat(129)).is_err());
+
+        for repo in ["", "App", "a//b", "a/", "/a", "a..b", "a/-b", "a:b"] {
+            assert!(OciArtifact::new("reg", repo.to_owned(), "latest").is_err(), "{}", repo);
+        }
+        assert!(OciArtifact::new("reg", "a.b/c_d/e-f".to_owned(), "latest").is_ok());
+    }
+
+    #[test]
+    fn digest_filters() {
+        let spec = format!("reg/app/{}", DIGEST);
+        assert_eq!(split_filter(&spec), Some((spec.as_str(), None)));
+        assert_eq!(
+            split_filter(&format!("{}:out/**", spec)),
+            Some((spec.as_str(), Some("out/**")))
+        );
+        assert_eq!(split_filter("reg/app/build-sha256:out"), None);
+        assert_eq!(split_filter("reg/app/sha256:abc:out"), None);
+    }
+
+    #[test]
+    fn layer_paths() {
+        let manifest = |json: &str| -> Manifest { serde_json::from_str(json).unwrap() };
+
+        let layers = manifest(
+            r#"{"layers": [
+                {"digest": "sha256:01",
+                 "annotations": {"org.opencontainers.image.title": "a/b.rpm"}},
+                {"digest": "sha256:02", "annotations": {"org.opencontainers.image.title": "../x"}},
+                {"digest": "sha256:03"}
+            ]}"#,
+        );
+        let names: Vec<_> = layers.layer_paths("t").unwrap().into_iter().map(|x| x.1).collect();
+        assert_eq!(names, vec!["a/b.rpm", "x", "sha256-03"]);
+
+        let duplicates = manifest(
+            r#"{"layers": [
+                {"digest": "sha256:01", "annotations": {"org.opencontainers.image.title": "a"}},
+                {"digest": "sha256:02", "annotations": {"org.opencontainers.image.title": "./a"}}
+            ]}"#,
+        );
+        assert!(duplicates.layer_paths("t").is_err());
+
+        let index = manifest(
+            r#"{"mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [{"digest": "sha256:01"}]}"#,
+        );
+        assert!(index.layer_paths("t").is_err());
+        let index = manifest(r#"{"manifests": [{"digest": "sha256:01"}]}"#);
+        assert!(index.layer_paths("t").is_err());
+    }
+
+    #[test]
+    fn tag_records() {
+        let repo_path =
+            std::env::temp_dir().join(format!("speardrive-oci-{}/reg/app", std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo_path);
+
+        assert_eq!(cached_tag_digest(&repo_path, "latest"), None);
+        record_tag_digest(&repo_path, "latest", DIGEST, "test");
+        // Only digests still cached are used.
+        assert_eq!(cached_tag_digest(&repo_path, "latest"), None);
+        std::fs::create_dir_all(repo_path.join(DIGEST)).unwrap();
+        assert_eq!(cached_tag_digest(&repo_path, "latest").as_deref(), Some(DIGEST));
+        assert_eq!(cached_tag_digest(&repo_path, "other"), None);
+
+        let _ = std::fs::remove_dir_all(repo_path.parent().unwrap().parent().unwrap());
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+
+use crate::{config::SpeardriveSource, error::Error, http, util};
+
+/// Path prefix under which a speardrive instance exports the cached artifacts
+/// of single source specs, for the use of its peers.
+pub const EXPORT_PREFIX: &str = "/_artifacts/";
+
+/// Header of the export response identifying what the spec resolved to.
+pub const IDENTITY_HEADER: &str = "x-speardrive-identity";
+
+/// Query parameter of an export request that makes it fail unless the spec
+/// still resolves to the given identity.
+pub const IDENTITY_PARAM: &str = "identity";
+
+/// Query parameter of a HEAD export request that makes the peer cache the
+/// spec from its own sources if it hasn't yet. Without it, a HEAD request
+/// only reports what is already cached.
+pub const PREPARE_PARAM: &str = "prepare";
+
+/// How long the peer waits for the spec to be cached before answering a HEAD
+/// request with 202, to be asked again. It keeps caching in the meantime.
+pub const PREPARE_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct PeerArtifact {
+    pub source_name: String,
+    /// A source spec as understood by the peer, e.g. `myserver/foo/323`.
+    pub spec: String,
+    /// What the spec resolved to on the peer, once it was asked.
+    pub identity: Option<String>,
+}
+
+impl PeerArtifact {
+    pub fn spec_path(&self, local_cache: &Path) -> PathBuf {
+        local_cache.join(&self.source_name).join(&self.spec)
+    }
+
+    pub fn cache_path(&self, local_cache: &Path) -> Option<PathBuf> {
+        let identity = self.identity.as_ref()?;
+        Some(self.spec_path(local_cache).join(identity))
+    }
+
+    fn export_url(&self, source: &SpeardriveSource) -> String {
+        format!("{}{}{}", source.base_url.trim_end_matches('/'), EXPORT_PREFIX, self.spec)
+    }
+}
+
+/// Identify the cached files of an exported spec: by their place in the local
+/// cache, whose entries don't change, and otherwise also by the sizes and
+/// modification times of the files.
+pub fn identity(local_cache: &Path, path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    match path.strip_prefix(local_cache) {
+        Ok(rel) => hasher.update(rel.to_string_lossy().as_bytes()),
+        Err(_) => {
+            hasher.update(path.to_string_lossy().as_bytes());
+            for rel in util::walk_files(path)? {
+                let metadata = std::fs::metadata(path.join(&rel))?;
+                let modified = metadata
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let line = format!("\n{}\t{}\t{:?}", rel.display(), metadata.len(), modified);
+                hasher.update(line.as_bytes());
+            }
+        }
+    }
+
+    Ok(hex::encode(hasher.finalize())[..16].to_owned())
+}
+
+/// Ask the peer what the spec resolves to, then fetch the artifact from the
+/// peer's export endpoint, as a tarball that is extracted into
+/// `<local_cache>/<source>/<spec>/<identity>`, unless it's already there.
+pub async fn cache_artifact(
+    local_cache: &Path,
+    pa: &mut PeerArtifact,
+    source: &SpeardriveSource,
+    uri: &str,
+) -> Result<(), Error> {
+    let url = pa.export_url(source);
+    let policy = http::policy(None);
+    let client = http::client_for(&policy);
+
+    // The peer may have to fetch the artifact from its own sources first,
+    // which can take longer than a read timeout.
+    let rsp = loop {
+        let req = client.head(&url).query(&[(PREPARE_PARAM, "1")]);
+        let rsp = http::send_with(&policy, req).await?;
+        if rsp.status() != reqwest::StatusCode::ACCEPTED {
+            break rsp;
+        }
+        log::info!("request: {}: waiting for the peer to cache {}", uri, pa.spec);
+        tokio::time::sleep(policy.backoff).await;
+    };
+    let identity = rsp
+        .headers()
+        .get(IDENTITY_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| Error::InvalidResponse(url.clone(), format!("no {}", IDENTITY_HEADER)))?
+        .to_owned();
+    pa.identity = Some(identity.clone());
+
+    let path = pa.cache_path(local_cache).unwrap();
+    if path.exists() {
+        log::info!("request: {}: peer artifact {} exists", uri, path.display());
+        return Ok(());
+    }
+
+    let parent = pa.spec_path(local_cache);
+    std::fs::create_dir_all(&parent)?;
+
+    let lockfile = std::fs::File::create(parent.join("lock"))?;
+    lockfile.lock_exclusive()?;
+    if path.exists() {
+        log::info!("request: {}: peer artifact {} exists", uri, path.display());
+        return Ok(());
+    }
+    util::begin_entry(&path)?;
+
+    let path_tmp = util::with_suffix(&path, ".tmp");
+    let tarball = util::with_suffix(&path, ".tar.tmp");
+    let _ = std::fs::remove_dir_all(&path_tmp);
+    std::fs::create_dir_all(&path_tmp)?;
+
+    log::info!("request: {}: fetching {} from peer", uri, url);
+
+    // The spec may resolve differently by now, which the peer refuses rather
+    // than have other files cached under this identity.
+    let req = client.get(&url).query(&[(IDENTITY_PARAM, &identity)]);
+    http::download_with(&policy, req, &tarball).await?;
+
+    log::info!("request: {}: extracting peer artifact", uri);
+    {
+        let tarball_path = tarball.display();
+        let path_tmp = path_tmp.display();
+        util::bash(format!("tar -xf {tarball_path} -C {path_tmp}"))?;
+    }
+    std::fs::remove_file(tarball)?;
+
+    log::info!("request: {}: placing peer artifact", uri);
+    util::commit_entry(&path_tmp, &path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities() {
+        let root = std::env::temp_dir().join(format!("speardrive-peer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let local_cache = root.join("cache");
+        let outside = root.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("a.rpm"), b"a").unwrap();
+
+        // Entries of the local cache are identified by their place alone.
+        let entry = local_cache.join("gl/group/proj/12");
+        assert_eq!(identity(&local_cache, &entry).unwrap().len(), 16);
+        assert_eq!(
+            identity(&local_cache, &entry).unwrap(),
+            identity(&local_cache, &local_cache.join("gl/group/proj/12")).unwrap()
+        );
+        assert_ne!(
+            identity(&local_cache, &entry).unwrap(),
+            identity(&local_cache, &local_cache.join("gl/group/proj/13")).unwrap()
+        );
+
+        // Other directories change in place.
+        let before = identity(&local_cache, &outside).unwrap();
+        std::fs::write(outside.join("b.rpm"), b"b").unwrap();
+        assert_ne!(identity(&local_cache, &outside).unwrap(), before);
+
+        let pa = PeerArtifact {
+            source_name: "dc1".to_owned(),
+            spec: "gl/group/proj/latest".to_owned(),
+            identity: None,
+        };
+        assert_eq!(pa.cache_path(&local_cache), None);
+        let pa = PeerArtifact { identity: Some("0123456789abcdef".to_owned()), ..pa };
+        assert_eq!(
+            pa.cache_path(&local_cache),
+            Some(local_cache.join("dc1/gl/group/proj/latest/0123456789abcdef"))
+        );
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::Error, util};
+
+/// Report of the packages removed by deduplication, in the composite root.
+pub const SHADOWED_FILE: &str = "shadowed.txt";
+
+/// Header tags queried from each package, separated by tabs.
+const QUERY_FORMAT: &str = "%{NAME}\t%{EPOCHNUM}\t%{VERSION}\t%{RELEASE}\t%{ARCH}\t%{SOURCERPM}";
+
+/// How to pick among packages of the same name and architecture that come
+/// from several sources of a plan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedup {
+    /// The earliest source in the plan wins.
+    First,
+    /// The latest source in the plan wins.
+    Last,
+    /// The package with the highest epoch, version and release wins, or of
+    /// the earliest source in the plan if several have it.
+    Evr,
+}
+
+/// Plan-level options of RPM composites, given in the query of the request,
+/// e.g. `?arch=x86_64,noarch&exclude-src=1&dist=el9&dedup=last`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    /// Keep only packages of these architectures. Source packages have the
+    /// `src` architecture.
+    pub arch: Vec<String>,
+    /// Drop the source packages.
+    pub exclude_src: bool,
+    /// Keep only packages whose release carries one of these dist tags.
+    pub dist: Vec<String>,
+    /// Keep a single version of the packages of the same name and
+    /// architecture, all kept if unset.
+    pub dedup: Option<Dedup>,
+}
+
+impl Options {
+    pub fn from_query(query: Option<&str>) -> Result<Self, Error> {
+        let mut options = Self::default();
+
+        let list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_owned())
+                .collect()
+        };
+
+        for pair in query.unwrap_or("").split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "arch" => options.arch.extend(list(value)),
+                "dist" => options.dist.extend(list(value)),
+                "exclude-src" => {
+                    options.exclude_src = match value {
+                        "" | "1" | "true" | "yes" => true,
+                        "0" | "false" | "no" => false,
+                        _ => {
+                            return Err(Error::PlanParse(format!(
+                                "{} invalid value for exclude-src",
+                                value
+                            )))
+                        }
+                    }
+                }
+                "dedup" => {
+                    options.dedup = Some(match value {
+                        "first" => Dedup::First,
+                        "last" => Dedup::Last,
+                        "evr" => Dedup::Evr,
+                        _ => {
+                            return Err(Error::PlanParse(format!(
+                                "{} invalid value for dedup, expected first, last or evr",
+                                value
+                            )))
+                        }
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        // The options are part of the composite's hash, which shouldn't
+        // depend on the order the lists were given in.
+        for list in [&mut options.arch, &mut options.dist] {
+            list.sort();
+            list.dedup();
+        }
+
+        Ok(options)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub epoch: u64,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+}
+
+impl Header {
+    pub fn is_source(&self) -> bool {
+        self.arch == "src"
+    }
+
+    pub fn nevra(&self) -> String {
+        format!(
+            "{}-{}:{}-{}.{}",
+            self.name, self.epoch, self.version, self.release, self.arch
+        )
+    }
+
+    fn cmp_evr(&self, other: &Header) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.version, &other.version))
+            .then_with(|| rpmvercmp(&self.release, &other.release))
+    }
+
+    /// Whether the release carries the dist tag, e.g. `el9` matches the
+    /// release `1.el9_2`.
+    fn has_dist(&self, dist: &str) -> bool {
+        self.release.split(['.', '+']).any(|x| {
+            x.strip_prefix(dist)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+        })
+    }
+}
+
+/// Compare version or release strings the way rpm does: alternating numeric
+/// and alphabetic segments, where `~` sorts before anything and `^` after the
+/// end of the string.
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let is_separator = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~' && *c != b'^';
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    loop {
+        while a.first().is_some_and(is_separator) {
+            a = &a[1..];
+        }
+        while b.first().is_some_and(is_separator) {
+            b = &b[1..];
+        }
+
+        if a.first() == Some(&b'~') || b.first() == Some(&b'~') {
+            if a.first() != Some(&b'~') {
+                return Ordering::Greater;
+            }
+            if b.first() != Some(&b'~') {
+                return Ordering::Less;
+            }
+            a = &a[1..];
+            b = &b[1..];
+            continue;
+        }
+
+        if a.first() == Some(&b'^') || b.first() == Some(&b'^') {
+            if a.is_empty() {
+                return Ordering::Less;
+            }
+            if b.is_empty() {
+                return Ordering::Greater;
+            }
+            if a[0] != b'^' {
+                return Ordering::Greater;
+            }
+            if b[0] != b'^' {
+                return Ordering::Less;
+            }
+            a = &a[1..];
+            b = &b[1..];
+            continue;
+        }
+
+        if a.is_empty() || b.is_empty() {
+            break;
+        }
+
+        let numeric = a[0].is_ascii_digit();
+        let segment = |s: &[u8]| {
+            s.iter()
+                .take_while(|c| {
+                    if numeric {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+        let (len_a, len_b) = (segment(a), segment(b));
+
+        // Numeric segments are newer than alphabetic ones.
+        if len_b == 0 {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let (mut seg_a, mut seg_b) = (&a[..len_a], &b[..len_b]);
+        if numeric {
+            while seg_a.len() > 1 && seg_a[0] == b'0' {
+                seg_a = &seg_a[1..];
+            }
+            while seg_b.len() > 1 && seg_b[0] == b'0' {
+                seg_b = &seg_b[1..];
+            }
+            match seg_a.len().cmp(&seg_b.len()) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+        }
+        match seg_a.cmp(seg_b) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+
+        a = &a[len_a..];
+        b = &b[len_b..];
+    }
+
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+/// Read the header of a package file.
+pub fn query_header(path: &Path) -> Result<Header, Error> {
+    let output = std::process::Command::new("rpm")
+        .args(["-qp", "--nosignature", "--nodigest", "--queryformat", QUERY_FORMAT])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::CommandError(
+            "rpm -qp".to_owned(),
+            path.display().to_string(),
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<_> = stdout.trim_end().split('\t').collect();
+    if let [name, epoch, version, release, arch, sourcerpm] = fields[..] {
+        Ok(Header {
+            name: name.to_owned(),
+            epoch: epoch.parse()?,
+            version: version.to_owned(),
+            release: release.to_owned(),
+            // Source packages carry the build architecture in their header,
+            // but have no source package of their own.
+            arch: if sourcerpm == "(none)" {
+                "src".to_owned()
+            } else {
+                arch.to_owned()
+            },
+        })
+    } else {
+        Err(Error::CommandError(
+            "rpm -qp".to_owned(),
+            format!("{}: unexpected output {:?}", path.display(), stdout),
+        ))
+    }
+}
+
+/// The headers of all the packages under `root`, by their relative paths.
+pub fn scan(root: &Path) -> Result<Vec<(PathBuf, Header)>, Error> {
+    let mut packages = vec![];
+
+    for rel in util::walk_files(root)? {
+        if rel.extension().is_some_and(|x| x == "rpm") {
+            let header = query_header(&root.join(&rel))?;
+            packages.push((rel, header));
+        }
+    }
+
+    Ok(packages)
+}
+
+/// The index of the plan source a package was placed from, being the first
+/// component of its path in the composite.
+fn source_index(rel: &Path) -> usize {
+    rel.iter()
+        .next()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The package that wins among a group of the same name and architecture.
+fn pick(group: &[(PathBuf, Header)], dedup: Dedup) -> Option<&(PathBuf, Header)> {
+    match dedup {
+        Dedup::First => group.iter().min_by_key(|(rel, _)| source_index(rel)),
+        Dedup::Last => group.iter().max_by_key(|(rel, _)| source_index(rel)),
+        Dedup::Evr => group.iter().max_by(|(rel_a, a), (rel_b, b)| {
+            a.cmp_evr(b)
+                .then_with(|| source_index(rel_b).cmp(&source_index(rel_a)))
+                .then_with(|| rel_b.cmp(rel_a))
+        }),
+    }
+}
+
+/// Whether the package at `rel` is kept next to the winner of its group.
+fn is_kept(rel: &Path, winner_rel: &Path, dedup: Dedup) -> bool {
+    match dedup {
+        // Packages of the winning source are all kept by plan order.
+        Dedup::First | Dedup::Last => source_index(rel) == source_index(winner_rel),
+        Dedup::Evr => rel == winner_rel,
+    }
+}
+
+/// Remove the packages under `root` that the options filter out or that are
+/// shadowed by packages of the same name and architecture, before the
+/// repository metadata is created. Shadowed packages are reported in
+/// `shadowed.txt`. Returns the number of packages removed.
+pub fn apply(root: &Path, options: &Options, uri: &str) -> Result<usize, Error> {
+    let mut removed = 0;
+    let mut packages = vec![];
+
+    for (rel, header) in scan(root)? {
+        let keep = (options.arch.is_empty() || options.arch.contains(&header.arch))
+            && !(options.exclude_src && header.is_source())
+            && (options.dist.is_empty() || options.dist.iter().any(|x| header.has_dist(x)));
+
+        if keep {
+            packages.push((rel, header));
+        } else {
+            log::info!("request: {}: filtering out {}", uri, header.nevra());
+            std::fs::remove_file(root.join(rel))?;
+            removed += 1;
+        }
+    }
+
+    let dedup = match options.dedup {
+        Some(dedup) => dedup,
+        None => return Ok(removed),
+    };
+
+    let mut groups: BTreeMap<(String, String), Vec<(PathBuf, Header)>> = BTreeMap::new();
+    for (rel, header) in packages {
+        groups
+            .entry((header.name.clone(), header.arch.clone()))
+            .or_default()
+            .push((rel, header));
+    }
+
+    let mut shadowed = String::new();
+    for (_, group) in groups {
+        let (winner_rel, winner) = match pick(&group, dedup) {
+            Some(winner) => winner,
+            None => continue,
+        };
+
+        for (rel, header) in group.iter() {
+            if is_kept(rel, winner_rel, dedup) {
+                continue;
+            }
+
+            log::info!(
+                "request: {}: {} shadowed by {}",
+                uri,
+                header.nevra(),
+                winner.nevra()
+            );
+            shadowed.push_str(&format!(
+                "{}\t{}\tshadowed-by\t{}\t{}\n",
+                rel.display(),
+                header.nevra(),
+                winner_rel.display(),
+                winner.nevra()
+            ));
+            std::fs::remove_file(root.join(rel))?;
+            removed += 1;
+        }
+    }
+
+    std::fs::write(root.join(SHADOWED_FILE), shadowed)?;
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(epoch: u64, version: &str, release: &str) -> Header {
+        Header {
+            name: "foo".to_owned(),
+            epoch,
+            version: version.to_owned(),
+            release: release.to_owned(),
+            arch: "x86_64".to_owned(),
+        }
+    }
+
+    fn group(packages: &[(&str, Header)]) -> Vec<(PathBuf, Header)> {
+        packages
+            .iter()
+            .map(|(rel, header)| (PathBuf::from(rel), header.clone()))
+            .collect()
+    }
+
+    fn winner(group: &[(PathBuf, Header)], dedup: Dedup) -> String {
+        pick(group, dedup).unwrap().0.display().to_string()
+    }
+
+    #[test]
+    fn vercmp_numeric_and_alphabetic() {
+        assert_eq!(rpmvercmp("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0", "1.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0a", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("2.0", "2a"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0a", "1.0b"), Ordering::Less);
+        assert_eq!(rpmvercmp("abc", "abd"), Ordering::Less);
+        // Numeric segments are newer than alphabetic ones.
+        assert_eq!(rpmvercmp("1.1", "1.a"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.a", "1.1"), Ordering::Less);
+        // Separators only separate.
+        assert_eq!(rpmvercmp("1_0", "1.0"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.el9", "1.el10"), Ordering::Less);
+    }
+
+    #[test]
+    fn vercmp_tilde_and_caret() {
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~~", "1.0~"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1", "0.9"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0^git2"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^", "1.0~"), Ordering::Greater);
+    }
+
+    #[test]
+    fn evr_epoch_first() {
+        assert_eq!(header(1, "1.0", "1").cmp_evr(&header(0, "2.0", "1")), Ordering::Greater);
+        assert_eq!(header(0, "1.0", "2").cmp_evr(&header(0, "1.0", "10")), Ordering::Less);
+        assert_eq!(header(0, "1.0", "1").cmp_evr(&header(0, "1.0", "1")), Ordering::Equal);
+    }
+
+    #[test]
+    fn dedup_first_and_last() {
+        let packages = group(&[
+            ("0/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("1/foo-2.0-1.rpm", header(0, "2.0", "1")),
+            ("1/el9/foo-2.0-2.rpm", header(0, "2.0", "2")),
+            ("2/foo-0.5-1.rpm", header(0, "0.5", "1")),
+        ]);
+        assert_eq!(winner(&packages, Dedup::First), "0/foo-1.0-1.rpm");
+        assert_eq!(winner(&packages, Dedup::Last), "2/foo-0.5-1.rpm");
+
+        // All the packages of the winning source are kept.
+        let winner_rel = Path::new("1/foo-2.0-1.rpm");
+        assert!(is_kept(Path::new("1/el9/foo-2.0-2.rpm"), winner_rel, Dedup::First));
+        assert!(!is_kept(Path::new("0/foo-1.0-1.rpm"), winner_rel, Dedup::First));
+
+        // Sources are ordered by their index, not by their paths.
+        let packages = group(&[
+            ("10/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("9/foo-1.0-1.rpm", header(0, "1.0", "1")),
+        ]);
+        assert_eq!(winner(&packages, Dedup::First), "9/foo-1.0-1.rpm");
+        assert_eq!(winner(&packages, Dedup::Last), "10/foo-1.0-1.rpm");
+    }
+
+    #[test]
+    fn dedup_evr() {
+        let packages = group(&[
+            ("0/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("1/foo-1.0~rc1-1.rpm", header(0, "1.0~rc1", "1")),
+            ("2/foo-0.9-1.rpm", header(1, "0.9", "1")),
+        ]);
+        assert_eq!(winner(&packages, Dedup::Evr), "2/foo-0.9-1.rpm");
+        assert!(!is_kept(Path::new("0/foo-1.0-1.rpm"), Path::new("2/foo-0.9-1.rpm"), Dedup::Evr));
+
+        // Ties across sources go to the earliest source, whatever the order.
+        let packages = group(&[
+            ("10/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("2/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("3/foo-1.0-1.rpm", header(0, "1.0", "1")),
+            ("1/foo-0.1-1.rpm", header(0, "0.1", "1")),
+        ]);
+        assert_eq!(winner(&packages, Dedup::Evr), "2/foo-1.0-1.rpm");
+        let reversed = packages.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(winner(&reversed, Dedup::Evr), "2/foo-1.0-1.rpm");
+    }
+
+    #[test]
+    fn options_from_query() {
+        assert!(Options::from_query(None).unwrap().is_default());
+        assert!(Options::from_query(Some("other=1")).unwrap().is_default());
+
+        let options = Options::from_query(Some(
+            "arch=x86_64,noarch&arch=src&exclude-src&dist=el9,&dedup=evr",
+        ))
+        .unwrap();
+        assert_eq!(options.arch, vec!["noarch", "src", "x86_64"]);
+        assert!(options.exclude_src);
+        assert_eq!(options.dist, vec!["el9"]);
+        assert_eq!(options.dedup, Some(Dedup::Evr));
+
+        assert!(!Options::from_query(Some("exclude-src=no")).unwrap().exclude_src);
+        assert_eq!(Options::from_query(Some("dedup=first")).unwrap().dedup, Some(Dedup::First));
+        assert_eq!(Options::from_query(Some("dedup=last")).unwrap().dedup, Some(Dedup::Last));
+        assert!(Options::from_query(Some("dedup=newest")).is_err());
+        assert!(Options::from_query(Some("exclude-src=maybe")).is_err());
+
+        assert_eq!(
+            Options::from_query(Some("arch=noarch,x86_64&dist=el9,el8")).unwrap(),
+            Options::from_query(Some("arch=x86_64,noarch,x86_64&dist=el8&dist=el9")).unwrap()
+        );
+    }
+
+    #[test]
+    fn dist_tags() {
+        assert!(header(0, "1.0", "1.el9").has_dist("el9"));
+        assert!(header(0, "1.0", "1.el9_2").has_dist("el9"));
+        assert!(!header(0, "1.0", "1.el90").has_dist("el9"));
+        assert!(!header(0, "1.0", "1.fc39").has_dist("el9"));
+    }
+}
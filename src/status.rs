@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{header, Body, Response, StatusCode};
+
+use crate::{check, config::Config, error::Error, gc};
+
+/// First components of URL paths that are served by the server itself, and
+/// that can't be source names.
+pub const RESERVED_NAMES: &[&str] = &["healthz", "readyz", "status", "_artifacts", "_explain"];
+
+/// How many of the latest failed requests are kept for the status page.
+const RECENT_FAILURES: usize = 20;
+
+/// How long the measured cache sizes are served before measuring again.
+const SIZES_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// How long the result of the readiness checks is reused by later probes.
+const READINESS_MAX_AGE: Duration = Duration::from_secs(5);
+
+struct Build {
+    uri: String,
+    started: Instant,
+}
+
+struct Failure {
+    uri: String,
+    error: String,
+    time: chrono::DateTime<chrono::Utc>,
+}
+
+/// The sizes of the caches, measured in the background since walking them
+/// takes a while.
+#[derive(Default)]
+struct CacheSizes {
+    sizes: Vec<(&'static str, PathBuf, Result<u64, String>)>,
+    measured: Option<(Instant, chrono::DateTime<chrono::Utc>)>,
+    measuring: bool,
+}
+
+#[derive(Default)]
+struct Tracker {
+    next_id: u64,
+    builds: BTreeMap<u64, Build>,
+    failures: VecDeque<Failure>,
+}
+
+lazy_static::lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+    static ref TRACKER: Mutex<Tracker> = Mutex::new(Tracker::default());
+    static ref SIZES: Mutex<CacheSizes> = Mutex::new(CacheSizes::default());
+    static ref READINESS: tokio::sync::Mutex<Option<(Instant, Vec<String>)>> =
+        tokio::sync::Mutex::new(None);
+}
+
+/// Registers a build as in progress for as long as it lives.
+pub struct BuildGuard {
+    id: u64,
+}
+
+impl BuildGuard {
+    pub fn new(uri: &str) -> Self {
+        let mut tracker = TRACKER.lock().unwrap();
+        let id = tracker.next_id;
+        tracker.next_id += 1;
+        tracker.builds.insert(
+            id,
+            Build {
+                uri: uri.to_owned(),
+                started: Instant::now(),
+            },
+        );
+        Self { id }
+    }
+}
+
+impl Drop for BuildGuard {
+    fn drop(&mut self) {
+        TRACKER.lock().unwrap().builds.remove(&self.id);
+    }
+}
+
+pub fn record_failure(uri: &str, error: &Error) {
+    let mut tracker = TRACKER.lock().unwrap();
+    if tracker.failures.len() >= RECENT_FAILURES {
+        tracker.failures.pop_front();
+    }
+    tracker.failures.push_back(Failure {
+        uri: uri.to_owned(),
+        error: error.to_string(),
+        time: chrono::Utc::now(),
+    });
+}
+
+/// Start counting the uptime.
+pub fn init() {
+    lazy_static::initialize(&STARTED);
+}
+
+fn text(status: StatusCode, text: String) -> Response<Body> {
+    let mut rsp = Response::new(Body::from(text));
+    *rsp.status_mut() = status;
+    rsp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    rsp
+}
+
+/// Serve `/healthz`, `/readyz` and `/status`, or return `None` for any other
+/// path.
+pub async fn serve(config: &Config, path: &str) -> Option<Result<Response<Body>, Error>> {
+    match path {
+        "/healthz" => Some(Ok(text(StatusCode::OK, "ok\n".to_owned()))),
+        "/readyz" => Some(Ok(readyz(config).await)),
+        "/status" => Some(Ok(status(config))),
+        _ => None,
+    }
+}
+
+/// Run the readiness checks off the runtime, as they write to the caches,
+/// and at most once per `READINESS_MAX_AGE` however often probes come.
+async fn readiness(config: &Config) -> Vec<String> {
+    let mut cached = READINESS.lock().await;
+    if let Some((checked, failures)) = &*cached {
+        if checked.elapsed() < READINESS_MAX_AGE {
+            return failures.clone();
+        }
+    }
+
+    let config = config.clone();
+    let failures = tokio::task::spawn_blocking(move || check::readiness(&config))
+        .await
+        .unwrap_or_else(|err| vec![err.to_string()]);
+    *cached = Some((Instant::now(), failures.clone()));
+    failures
+}
+
+/// Start measuring the caches in the background, unless a measurement is
+/// running or the last one is recent enough.
+fn refresh_sizes(config: &Config) {
+    let mut sizes = SIZES.lock().unwrap();
+    let fresh = sizes.measured.is_some_and(|(at, _)| at.elapsed() < SIZES_MAX_AGE);
+    if sizes.measuring || fresh {
+        return;
+    }
+    sizes.measuring = true;
+
+    let roots = [
+        ("composites-cache", config.composites_cache.clone()),
+        ("local-cache", config.local_cache.clone()),
+    ];
+    tokio::task::spawn_blocking(move || {
+        let measured: Vec<_> = roots
+            .into_iter()
+            .map(|(name, root)| {
+                let size = gc::cache_size(&root).map_err(|e| e.to_string());
+                (name, root, size)
+            })
+            .collect();
+
+        let mut sizes = SIZES.lock().unwrap();
+        sizes.sizes = measured;
+        sizes.measured = Some((Instant::now(), chrono::Utc::now()));
+        sizes.measuring = false;
+    });
+}
+
+async fn readyz(config: &Config) -> Response<Body> {
+    let failures = readiness(config).await;
+    if failures.is_empty() {
+        return text(StatusCode::OK, "ready\n".to_owned());
+    }
+
+    let mut out = String::new();
+    for failure in failures {
+        let _ = writeln!(out, "{}", failure);
+    }
+    text(StatusCode::SERVICE_UNAVAILABLE, out)
+}
+
+fn status(config: &Config) -> Response<Body> {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "speardrive {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, "uptime: {}s", STARTED.elapsed().as_secs());
+
+    refresh_sizes(config);
+    {
+        let sizes = SIZES.lock().unwrap();
+        match sizes.measured {
+            Some((_, time)) => {
+                let _ = writeln!(out, "\ncaches (measured {}):", time.to_rfc3339());
+            }
+            None => {
+                let _ = writeln!(out, "\ncaches (being measured)");
+            }
+        }
+        for (name, root, size) in sizes.sizes.iter() {
+            match size {
+                Ok(size) => {
+                    let _ = writeln!(out, "  {}: {} ({} bytes)", name, root.display(), size);
+                }
+                Err(err) => {
+                    let _ = writeln!(out, "  {}: {} ({})", name, root.display(), err);
+                }
+            }
+        }
+    }
+
+    let tracker = TRACKER.lock().unwrap();
+
+    let _ = writeln!(out, "\nbuilds in progress: {}", tracker.builds.len());
+    for build in tracker.builds.values() {
+        let _ = writeln!(out, "  {} ({}s)", build.uri, build.started.elapsed().as_secs());
+    }
+
+    let _ = writeln!(out, "\nrecent failures: {}", tracker.failures.len());
+    for failure in tracker.failures.iter().rev() {
+        let _ = writeln!(
+            out,
+            "  {} {}: {}",
+            failure.time.to_rfc3339(),
+            failure.uri,
+            failure.error
+        );
+    }
+
+    text(StatusCode::OK, out)
+}
@@ -1,10 +1,328 @@
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
 use crate::error::Error;
 
 pub fn bash(cmd: String) -> Result<(), Error> {
     use std::process::Command;
     let status = Command::new("bash").arg("-c").arg(&cmd).status()?;
     if !status.success() {
-        return Err(Error::CommandError("bash".to_owned(), cmd).into());
+        return Err(Error::CommandError("bash".to_owned(), cmd));
+    }
+    Ok(())
+}
+
+/// Suffix of the file next to a directory of the local cache that marks it as
+/// a complete entry.
+pub const ENTRY_MARKER: &str = ".cached";
+
+/// Suffix of the file next to an entry of the local cache while it's being
+/// built. The build's leftovers are named after the entry too, with the
+/// `TMP_SUFFIXES`.
+pub const BUILD_MARKER: &str = ".building";
+
+/// Suffixes of the temporary directory and files of the build of an entry.
+pub const TMP_SUFFIXES: &[&str] = &[".tmp", ".tar.tmp"];
+
+/// `path` with a suffix appended to its file name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Note that the entry `path` of the local cache is being built, so that its
+/// leftovers can be found if the build is interrupted.
+pub fn begin_entry(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
     }
+    std::fs::write(with_suffix(path, BUILD_MARKER), b"")?;
     Ok(())
 }
+
+/// Move a built entry of the local cache into place and mark it complete.
+pub fn commit_entry(tmp: &Path, path: &Path) -> Result<(), Error> {
+    std::fs::rename(tmp, path)?;
+    std::fs::write(with_suffix(path, ENTRY_MARKER), b"")?;
+    let _ = std::fs::remove_file(with_suffix(path, BUILD_MARKER));
+    Ok(())
+}
+
+/// Remove the leftovers of a failed build of the entry `path` of the local
+/// cache.
+pub fn abort_entry(tmp: &Path, path: &Path) {
+    let _ = std::fs::remove_dir_all(tmp);
+    let _ = std::fs::remove_file(with_suffix(path, BUILD_MARKER));
+}
+
+/// Shared locks on the cache entries and composites a request uses, which gc
+/// doesn't remove while they are held. They are released on drop.
+#[derive(Debug, Default)]
+pub struct InUse(Vec<std::fs::File>);
+
+impl InUse {
+    /// Lock the directory `path` as in use. Returns whether it's there, and
+    /// not removed or replaced before the lock was taken.
+    pub fn add(&mut self, path: &Path) -> Result<bool, Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = match std::fs::File::open(path) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        fs2::FileExt::lock_shared(&dir)?;
+
+        let locked = dir.metadata()?;
+        let current = match std::fs::metadata(path) {
+            Ok(current) => current,
+            Err(_) => return Ok(false),
+        };
+        if (locked.dev(), locked.ino()) != (current.dev(), current.ino()) {
+            return Ok(false);
+        }
+
+        self.0.push(dir);
+        Ok(true)
+    }
+}
+
+/// Make a relative path out of an untrusted one, dropping empty, `.` and `..`
+/// components.
+pub fn sanitize_rel_path(path: &str) -> String {
+    path.split('/')
+        .filter(|x| !x.is_empty() && *x != "." && *x != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Decode the `%XX` escapes of a URL path component.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Escape all but the unreserved characters of a URL path component.
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Include and exclude glob patterns over relative paths, where `*` stays
+/// within a directory and `**` crosses directories.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, Error> {
+        let build = |patterns: &[String]| -> Result<GlobSet, Error> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+            }
+            Ok(builder.build()?)
+        };
+
+        Ok(Self {
+            include: if include.is_empty() { None } else { Some(build(include)?) },
+            exclude: build(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let included = match &self.include {
+            Some(include) => include.is_match(path),
+            None => true,
+        };
+
+        included && !self.exclude.is_match(path)
+    }
+}
+
+/// The relative paths of all the files under `root`, sorted. Symlinks to
+/// files are listed, but symlinks to directories are skipped rather than
+/// followed, as they may loop, and so are dangling ones.
+pub fn walk_files(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    fn walk(root: &Path, rel: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+        for entry in std::fs::read_dir(root.join(rel))? {
+            let entry = entry?;
+            let rel = rel.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                walk(root, &rel, files)?;
+            } else if !file_type.is_symlink()
+                || std::fs::metadata(entry.path()).is_ok_and(|x| !x.is_dir())
+            {
+                files.push(rel);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    walk(root, Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Whether two files have the same contents.
+pub fn same_contents(a: &Path, b: &Path) -> Result<bool, Error> {
+    use std::io::Read;
+
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = std::fs::File::open(a)?;
+    let mut b = std::fs::File::open(b)?;
+    let mut buf_a = vec![0u8; 0x10000];
+    let mut buf_b = vec![0u8; 0x10000];
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Hardlink, or copy if that fails, the files under `src` that match the
+/// filter into `dest`, keeping their relative paths. Returns the number of
+/// files placed.
+pub fn place_filtered(src: &Path, dest: &Path, filter: &PathFilter) -> Result<usize, Error> {
+    let mut placed = 0;
+
+    std::fs::create_dir_all(dest)?;
+    for rel in walk_files(src)? {
+        if !filter.matches(&rel.to_string_lossy()) {
+            continue;
+        }
+
+        let target = dest.join(&rel);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if std::fs::hard_link(src.join(&rel), &target).is_err() {
+            std::fs::copy(src.join(&rel), &target)?;
+        }
+        placed += 1;
+    }
+
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let strings = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&strings(include), &strings(exclude)).unwrap()
+    }
+
+    #[test]
+    fn single_star_stays_in_directory() {
+        let f = filter(&["rpms/*.rpm"], &[]);
+        assert!(f.matches("rpms/a.rpm"));
+        assert!(!f.matches("rpms/el9/a.rpm"));
+        assert!(!f.matches("a.rpm"));
+
+        let f = filter(&["*.rpm"], &[]);
+        assert!(f.matches("a.rpm"));
+        assert!(!f.matches("rpms/a.rpm"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let f = filter(&["rpms/**/*.rpm"], &[]);
+        assert!(f.matches("rpms/a.rpm"));
+        assert!(f.matches("rpms/el9/x86_64/a.rpm"));
+        assert!(!f.matches("logs/a.rpm"));
+        assert!(!f.matches("rpms/el9/a.log"));
+
+        let f = filter(&["**/*.rpm"], &[]);
+        assert!(f.matches("a.rpm"));
+        assert!(f.matches("el9/a.rpm"));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        // As in the example `include` and `exclude` of a remote source.
+        let f = filter(&["**/*.rpm"], &["**/debug/**"]);
+        assert!(f.matches("el9/a.rpm"));
+        assert!(!f.matches("el9/debug/a-debuginfo.rpm"));
+        assert!(!f.matches("debug/a-debuginfo.rpm"));
+        assert!(!f.matches("el9/a.log"));
+
+        // Without includes, everything not excluded matches.
+        let f = filter(&[], &["*.log"]);
+        assert!(f.matches("a.rpm"));
+        assert!(!f.matches("build.log"));
+        assert!(f.matches("logs/build.log"));
+
+        assert!(PathFilter::new(&["a/[".to_owned()], &[]).is_err());
+    }
+
+    #[test]
+    fn subdirectory_filter() {
+        // How a spec filter without wildcards selects a subdirectory.
+        let f = filter(&["out/x86_64", "out/x86_64/**"], &[]);
+        assert!(f.matches("out/x86_64/a.rpm"));
+        assert!(f.matches("out/x86_64/sub/a.rpm"));
+        assert!(!f.matches("out/aarch64/a.rpm"));
+        assert!(!f.matches("out/x86_64.rpm"));
+    }
+
+    #[test]
+    fn walk_skips_directory_symlinks() {
+        let root = std::env::temp_dir().join(format!("speardrive-walk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let src = root.join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.rpm"), b"a").unwrap();
+        std::fs::write(src.join("sub/b.rpm"), b"b").unwrap();
+        std::os::unix::fs::symlink("..", src.join("sub/up")).unwrap();
+        std::os::unix::fs::symlink(".", src.join("latest")).unwrap();
+        std::os::unix::fs::symlink("a.rpm", src.join("c.rpm")).unwrap();
+        std::os::unix::fs::symlink("missing", src.join("d.rpm")).unwrap();
+
+        let files: Vec<PathBuf> =
+            ["a.rpm", "c.rpm", "sub/b.rpm"].iter().map(PathBuf::from).collect();
+        assert_eq!(walk_files(&src).unwrap(), files);
+
+        let dest = root.join("dest");
+        assert_eq!(place_filtered(&src, &dest, &filter(&["**"], &[])).unwrap(), 3);
+        assert_eq!(std::fs::read(dest.join("c.rpm")).unwrap(), b"a");
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}